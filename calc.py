@@ -1,5 +1,6 @@
 from __future__ import annotations
 
+import os
 import sys
 import json
 import asyncio
@@ -44,6 +45,7 @@ class Calculator:
         self.r = r
         self.w = w
         self.previous: List[Previous] = [Previous.empty()]
+        self.seq = 0
 
     def iter_previous(self, *, include_last: bool = True):
         last = len(self.previous) - 1
@@ -58,6 +60,11 @@ class Calculator:
         r, w = await asyncio.open_connection(host, port)
         return cls(r, w)
 
+    @classmethod
+    async def connect_unix(cls, path: str) -> Calculator:
+        r, w = await asyncio.open_unix_connection(path)
+        return cls(r, w)
+
     async def send(self, message: Any) -> None:
         self.w.write(json.dumps(message).encode())
         self.w.write(b'\n')
@@ -67,19 +74,28 @@ class Calculator:
         return json.loads(await self.r.readline())
 
     async def register(self) -> int:
-        await self.send({'subscribe_to': 0b1101, 'protocol_version': 0, 'matcher': 'none'})
+        await self.send(
+            {
+                'subscribe_to': 0b1101,
+                'protocol_version': 1,
+                'matcher': 'none',
+                'debounce_ms': 50,
+            }
+        )
 
-        first_message = await self.recv()
-        if first_message['key'] == 'busy':
-            return (await self.recv())['data']
-        else:
-            return first_message['data']
+        while True:
+            message = await self.recv()
+            if message['key'] == 'registered':
+                return message['data']
+            elif message['key'] != 'queued':
+                raise RuntimeError(f'failed to register: {message["key"]}')
 
     async def set_choices(self) -> None:
         await self.send(
             {
                 'key': 'set_choices',
                 'data': {
+                    'seq': self.seq,
                     'options': [
                         {
                             'text': f'{idx}: {p.inp.strip()} {p.equals_sign} {p.out}',
@@ -121,6 +137,8 @@ class Calculator:
                     break
 
             if key == 'input_change':
+                self.seq = data['seq']
+                data = data['input']
                 curr = self.previous[-1]
                 try:
                     curr.broken = False
@@ -135,14 +153,16 @@ class Calculator:
                 else:
                     self.previous[-1] = Previous(data, out)
 
-            if key == 'window_closed':
+            if key in ('window_closed', 'shutting_down'):
                 return
 
         await self.send(json.dumps({'key': 'stop'}))
 
 
 async def main() -> None:
-    calc = await Calculator.connect('127.0.0.1', 5555)
+    calc = await Calculator.connect_unix(
+        os.path.join(os.environ['XDG_RUNTIME_DIR'], 'uuis.sock')
+    )
     await calc.run()
 
 
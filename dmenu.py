@@ -1,43 +1,99 @@
+import os
 import sys
+import stat
 import json
 import asyncio
 
 
+CHUNK_SIZE = 1000
+
+
+def socket_path():
+    return os.path.join(os.environ['XDG_RUNTIME_DIR'], 'uuis.sock')
+
+
+async def stdin_reader():
+    """Coroutine function reading a line from stdin, b'' at EOF"""
+    loop = asyncio.get_running_loop()
+    mode = os.fstat(sys.stdin.fileno()).st_mode
+    if not (stat.S_ISFIFO(mode) or stat.S_ISSOCK(mode) or stat.S_ISCHR(mode)):
+        # Pipe transports don't support regular files, so read them on a thread
+        return lambda: loop.run_in_executor(None, sys.stdin.buffer.readline)
+
+    reader = asyncio.StreamReader()
+    await loop.connect_read_pipe(
+        lambda: asyncio.StreamReaderProtocol(reader), sys.stdin
+    )
+    return reader.readline
+
+
+async def read_chunk(readline):
+    chunk = []
+    while len(chunk) < CHUNK_SIZE:
+        line = await readline()
+        if not line:
+            break
+        chunk.append(line.decode().strip())
+    return chunk
+
+
 async def main():
-    options = list(map(str.strip, sys.stdin))
-    r, w = await asyncio.open_connection('127.0.0.1', 5555)
-    w.write((json.dumps({'subscribe_to': 1, 'protocol_version': 0}) + '\n').encode())
+    options = []
+    r, w = await asyncio.open_unix_connection(socket_path())
+    w.write((json.dumps({'subscribe_to': 1, 'protocol_version': 1}) + '\n').encode())
     await w.drain()
 
-    first_message = json.loads(await r.readline())
-    if first_message['key'] == 'busy':
-        client_id = json.loads(await r.readline())['data']
-    else:
-        client_id = first_message['data']
+    while True:
+        message = json.loads(await r.readline())
+        if message['key'] == 'registered':
+            client_id = message['data']
+            break
+        elif message['key'] == 'queued':
+            position = message['data']['position']
+            print(f'Waiting in queue, position {position}', file=sys.stderr)
+        else:
+            print(f'Failed to register: {message["key"]}', file=sys.stderr)
+            sys.exit(1)
 
     print(f'Client ID: {client_id}', file=sys.stderr)
 
-    w.write(
-        (
-            json.dumps(
-                {
-                    'key': 'set_choices',
-                    'data': {
-                        'options': [
-                            {'text': option, 'id': idx}
-                            for idx, option
-                            in enumerate(options)
-                        ],
-                    }
-                }
-            )
-            + '\n'
-        ).encode()
-    )
-    await w.drain()
+    async def send(message):
+        w.write((json.dumps(message) + '\n').encode())
+        await w.drain()
 
-    option = json.loads(await r.readline())['data']
-    print(options[option])
+    async def stream_options():
+        await send({'key': 'set_choices', 'data': {'loading': True, 'options': []}})
+        readline = await stdin_reader()
+        while True:
+            chunk = await read_chunk(readline)
+            if not chunk:
+                break
+            choices = [
+                {'text': option, 'id': len(options) + idx}
+                for idx, option
+                in enumerate(chunk)
+            ]
+            options.extend(chunk)
+            await send({'key': 'append_choices', 'data': choices})
+        await send({'key': 'choices_complete'})
+
+    streaming = asyncio.create_task(stream_options())
+    reply = asyncio.create_task(r.readline())
+
+    done, _ = await asyncio.wait(
+        {streaming, reply}, return_when=asyncio.FIRST_COMPLETED
+    )
+    if streaming in done:
+        # Re-raise errors from reading stdin instead of waiting forever
+        streaming.result()
+        line = await reply
+    else:
+        streaming.cancel()
+        line = reply.result()
+    message = json.loads(line)
+    if message['key'] != 'select' or message['data'] is None:
+        sys.exit(1)
+    print(options[message['data']])
 
 
 if __name__ == '__main__':
@@ -0,0 +1,69 @@
+use std::{
+    fmt,
+    fs::{self, OpenOptions},
+    io::{self, ErrorKind, Read as _, Write as _},
+    os::unix::fs::OpenOptionsExt as _,
+    path::Path,
+};
+
+const TOKEN_BYTES: usize = 32;
+
+/// Shared secret clients must present in their registration
+#[derive(Clone)]
+pub struct Token(String);
+
+impl Token {
+    /// Read token from `path`, generating a new one if the file doesn't exist
+    pub fn load_or_create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        match fs::read_to_string(path) {
+            Ok(token) => {
+                let token = token.trim();
+                if token.is_empty() {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("token file {} is empty", path.display()),
+                    ));
+                }
+                Ok(Self(token.to_owned()))
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => Self::create(path),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn create(path: &Path) -> io::Result<Self> {
+        let mut random = [0; TOKEN_BYTES];
+        fs::File::open("/dev/urandom")?.read_exact(&mut random)?;
+        let token: String = random.iter().map(|byte| format!("{:02x}", byte)).collect();
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?;
+        writeln!(file, "{}", token)?;
+        tracing::info!("generated new token in {}", path.display());
+
+        Ok(Self(token))
+    }
+
+    /// Compare with the client-provided token in constant time
+    #[must_use]
+    pub fn matches(&self, candidate: &str) -> bool {
+        let expected = self.0.as_bytes();
+        let candidate = candidate.as_bytes();
+        expected.len() == candidate.len()
+            && expected
+                .iter()
+                .zip(candidate)
+                .fold(0, |acc, (a, b)| acc | (a ^ b))
+                == 0
+    }
+}
+
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Token(..)")
+    }
+}
@@ -0,0 +1,70 @@
+use std::path::PathBuf;
+
+use color_eyre::eyre::{self, eyre};
+use structopt::StructOpt;
+
+use crate::{auth::Token, server::Address, util};
+
+const DEFAULT_SOCKET_NAME: &str = "uuis.sock";
+const DEFAULT_TOKEN_NAME: &str = "uuis.token";
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "uuis", about = "Universal UI server")]
+pub struct Opts {
+    /// Listen on the given TCP address; can be repeated
+    #[structopt(long, value_name = "ADDR", number_of_values = 1)]
+    pub tcp: Vec<String>,
+    /// Listen on the given Unix socket; can be repeated
+    #[structopt(long, value_name = "PATH", number_of_values = 1, parse(from_os_str))]
+    pub unix: Vec<PathBuf>,
+    /// Require TCP clients to present the token from `$XDG_RUNTIME_DIR/uuis.token`
+    #[structopt(long)]
+    pub auth: bool,
+    /// Read the token from this file instead; implies --auth
+    #[structopt(long, value_name = "PATH", parse(from_os_str))]
+    pub token_file: Option<PathBuf>,
+    /// Also accept Unix socket clients running as this user; can be repeated
+    #[structopt(long, value_name = "UID", number_of_values = 1)]
+    pub allow_uid: Vec<u32>,
+    /// Give every client its own window instead of queueing them
+    #[structopt(long)]
+    pub multi_window: bool,
+}
+
+impl Opts {
+    /// All addresses to listen on
+    ///
+    /// If none were given and `use_default` is set, falls back to `$XDG_RUNTIME_DIR/uuis.sock`.
+    pub fn addresses(&self, use_default: bool) -> eyre::Result<Vec<Address>> {
+        let mut addresses: Vec<_> = self
+            .tcp
+            .iter()
+            .cloned()
+            .map(Address::Tcp)
+            .chain(self.unix.iter().cloned().map(Address::Unix))
+            .collect();
+
+        if addresses.is_empty() && use_default {
+            let runtime_dir = util::runtime_dir().ok_or_else(|| {
+                eyre!("$XDG_RUNTIME_DIR is not set, pass --unix or --tcp explicitly")
+            })?;
+            addresses.push(Address::Unix(runtime_dir.join(DEFAULT_SOCKET_NAME)));
+        }
+
+        Ok(addresses)
+    }
+
+    /// Token TCP clients must authenticate with, if requested
+    ///
+    /// The token file is created if it doesn't exist.
+    pub fn token(&self) -> eyre::Result<Option<Token>> {
+        let path = match &self.token_file {
+            Some(path) => path.clone(),
+            None if self.auth => util::runtime_dir()
+                .ok_or_else(|| eyre!("$XDG_RUNTIME_DIR is not set, pass --token-file explicitly"))?
+                .join(DEFAULT_TOKEN_NAME),
+            None => return Ok(None),
+        };
+        Ok(Some(Token::load_or_create(path)?))
+    }
+}
@@ -0,0 +1,312 @@
+use std::{
+    cmp,
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::ErrorKind,
+    os::unix::io::AsRawFd as _,
+    path::PathBuf,
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use color_eyre::eyre::{self, bail, eyre, WrapErr as _};
+use nix::fcntl::{flock, FlockArg};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    types::{Choice, ClientRequest},
+    util,
+};
+
+/// Uses lose half of their weight over this many seconds
+const HALF_LIFE_SECS: f64 = 7.0 * 24.0 * 60.0 * 60.0;
+/// Entries kept per history, the least used ones are dropped first
+const MAX_ENTRIES: usize = 1000;
+/// Bonus of a choice selected once just now; further uses add logarithmically less
+const BONUS_SCALE: f64 = 32.0;
+
+/// Distinguishes temporary files written by this process
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    text: String,
+    /// Id of the choice when it was last selected
+    id: usize,
+    /// Number of uses, decayed as of `last_used`
+    uses: f64,
+    /// Seconds since the Unix epoch
+    last_used: u64,
+}
+
+impl Entry {
+    #[allow(clippy::cast_precision_loss)]
+    fn uses_at(&self, now: u64) -> f64 {
+        let age = now.saturating_sub(self.last_used) as f64;
+        self.uses * (-age / HALF_LIFE_SECS).exp2()
+    }
+}
+
+/// Selections of clients registered with the same `history_key`
+#[derive(Debug, Clone)]
+pub struct History {
+    path: PathBuf,
+}
+
+impl History {
+    /// History stored under `$XDG_STATE_HOME/uuis/history`
+    ///
+    /// Fails if the key can't be used as a file name or there's no state directory.
+    pub fn open(key: &str) -> eyre::Result<Self> {
+        if !is_valid_key(key) {
+            bail!("invalid history key {:?}", key);
+        }
+
+        let dir =
+            util::state_dir().ok_or_else(|| eyre!("neither $XDG_STATE_HOME nor $HOME is set"))?;
+        Ok(History {
+            path: dir.join("uuis/history").join(format!("{}.json", key)),
+        })
+    }
+
+    fn load(&self) -> eyre::Result<Vec<Entry>> {
+        match fs::read(&self.path) {
+            Ok(raw) => serde_json::from_slice(&raw)
+                .wrap_err_with(|| format!("failed to parse {}", self.path.display())),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            Err(err) => {
+                Err(err).wrap_err_with(|| format!("failed to read {}", self.path.display()))
+            }
+        }
+    }
+
+    /// Take an exclusive lock on a file next to the history, released when it's dropped
+    fn lock(&self) -> eyre::Result<File> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)
+                .wrap_err_with(|| format!("failed to create {}", dir.display()))?;
+        }
+        let path = self.path.with_extension("lock");
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(&path)
+            .wrap_err_with(|| format!("failed to open {}", path.display()))?;
+        flock(file.as_raw_fd(), FlockArg::LockExclusive)
+            .wrap_err_with(|| format!("failed to lock {}", path.display()))?;
+        Ok(file)
+    }
+
+    /// Replace the file at once, so concurrent readers never see it half-written
+    ///
+    /// The directory must exist already, which `lock` makes sure of.
+    fn save(&self, entries: &[Entry]) -> eyre::Result<()> {
+        let tmp = self.path.with_extension(format!(
+            "json.{}.{}.tmp",
+            process::id(),
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&tmp, serde_json::to_vec(entries)?)
+            .wrap_err_with(|| format!("failed to write {}", tmp.display()))?;
+        fs::rename(&tmp, &self.path)
+            .wrap_err_with(|| format!("failed to replace {}", self.path.display()))?;
+        Ok(())
+    }
+
+    /// Frecency bonuses of the choices in the history as of now
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn bonuses(&self) -> eyre::Result<Bonuses> {
+        let now = now();
+        let bonuses = self
+            .load()?
+            .into_iter()
+            .map(|entry| {
+                let bonus = (entry.uses_at(now).ln_1p() * BONUS_SCALE) as i64;
+                (entry.text, bonus)
+            })
+            .collect();
+        Ok(Bonuses(bonuses))
+    }
+
+    /// Count a use of the choice, dropping the least used entries if there are too many
+    ///
+    /// The file is read again under a lock, so other clients sharing the key don't lose
+    /// their selections.
+    pub fn record(&self, choice: &Choice) -> eyre::Result<()> {
+        let _lock = self.lock()?;
+        let now = now();
+        let mut entries = self.load()?;
+        match entries.iter_mut().find(|entry| entry.text == *choice.text) {
+            Some(entry) => {
+                entry.uses = entry.uses_at(now) + 1.0;
+                entry.id = choice.id;
+                entry.last_used = now;
+            }
+            None => entries.push(Entry {
+                text: String::from(&*choice.text),
+                id: choice.id,
+                uses: 1.0,
+                last_used: now,
+            }),
+        }
+
+        keep_most_used(&mut entries, now);
+        self.save(&entries)
+    }
+}
+
+/// Whether the key is safe to use as a file name
+fn is_valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && !key.starts_with('.')
+        && key
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || matches!(ch, '-' | '_' | '.'))
+}
+
+/// Drop the least used entries as of `now` if there are more than `MAX_ENTRIES`
+fn keep_most_used(entries: &mut Vec<Entry>, now: u64) {
+    if entries.len() > MAX_ENTRIES {
+        entries.sort_by(|a, b| {
+            b.uses_at(now)
+                .partial_cmp(&a.uses_at(now))
+                .unwrap_or(cmp::Ordering::Equal)
+        });
+        entries.truncate(MAX_ENTRIES);
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// Frecency bonuses by choice text
+///
+/// Choices are matched by text rather than id, since ids are often just positions in
+/// the client's list.
+#[derive(Debug, Default)]
+pub struct Bonuses(HashMap<String, i64>);
+
+impl Bonuses {
+    fn apply_to(&self, choice: &mut Choice) {
+        if let Some(&bonus) = self.0.get(&*choice.text) {
+            choice.set_bonus(bonus);
+        }
+    }
+
+    /// Give choices sent by the client their bonuses
+    pub fn apply(&self, req: &mut ClientRequest) {
+        if self.0.is_empty() {
+            return;
+        }
+
+        match req {
+            ClientRequest::SetChoices(set) => {
+                // Choices move around, so keep the same one selected
+                let selected_id = set.choices.selected_id();
+                set.choices.options = set
+                    .choices
+                    .options
+                    .iter()
+                    .cloned()
+                    .map(|mut choice| {
+                        self.apply_to(&mut choice);
+                        choice
+                    })
+                    .collect();
+                if let Some(id) = selected_id {
+                    set.choices.selected = set
+                        .choices
+                        .options
+                        .iter()
+                        .position(|choice| choice.id == id);
+                }
+            }
+            ClientRequest::AppendChoices(choices) => {
+                choices.iter_mut().for_each(|choice| self.apply_to(choice));
+            }
+            ClientRequest::UpdateChoice(choice) => self.apply_to(choice),
+            ClientRequest::Stop
+            | ClientRequest::RemoveChoices { .. }
+            | ClientRequest::ChoicesComplete
+            | ClientRequest::SetMatcher(_)
+            | ClientRequest::SetInput(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(text: &str, uses: f64, last_used: u64) -> Entry {
+        Entry {
+            text: text.to_owned(),
+            id: 0,
+            uses,
+            last_used,
+        }
+    }
+
+    #[test]
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    fn halves_uses_every_half_life() {
+        let half_life = HALF_LIFE_SECS as u64;
+        let entry = entry("a", 4.0, 100);
+        assert!((entry.uses_at(100) - 4.0).abs() < 1e-9);
+        assert!((entry.uses_at(100 + half_life) - 2.0).abs() < 1e-9);
+        assert!((entry.uses_at(100 + 2 * half_life) - 1.0).abs() < 1e-9);
+        // Clocks going backwards don't add uses
+        assert!((entry.uses_at(0) - 4.0).abs() < 1e-9);
+    }
+
+    #[test]
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_precision_loss,
+        clippy::cast_sign_loss
+    )]
+    fn drops_least_used_entries() {
+        let now = (20.0 * HALF_LIFE_SECS) as u64;
+        let mut entries: Vec<_> = (0..=MAX_ENTRIES)
+            .map(|idx| entry(&idx.to_string(), idx as f64 + 1.0, now))
+            .collect();
+        // Used the most, but so long ago that it's worth less than the rest
+        entries.push(entry("old", 1000.0, 0));
+
+        keep_most_used(&mut entries, now);
+        assert_eq!(entries.len(), MAX_ENTRIES);
+        let first = entries.first().map(|entry| entry.text.as_str());
+        let last = entries.last().map(|entry| entry.text.as_str());
+        assert_eq!(first, Some(&*MAX_ENTRIES.to_string()));
+        assert_eq!(last, Some("1"));
+        assert!(entries
+            .iter()
+            .all(|entry| entry.text != "0" && entry.text != "old"));
+    }
+
+    #[test]
+    fn keeps_entries_under_the_limit() {
+        let mut entries = vec![entry("a", 1.0, 0), entry("b", 2.0, 0)];
+        keep_most_used(&mut entries, 0);
+        let texts: Vec<_> = entries.iter().map(|entry| entry.text.as_str()).collect();
+        assert_eq!(texts, ["a", "b"]);
+    }
+
+    #[test]
+    fn accepts_file_name_keys() {
+        assert!(is_valid_key("proj-1_x.y"));
+        assert!(is_valid_key("a.b.c"));
+    }
+
+    #[test]
+    fn rejects_unsafe_keys() {
+        for key in ["", ".", "..", ".hidden", "a/b", "../a", "a b", "caf\u{e9}"] {
+            assert!(!is_valid_key(key), "{:?} is accepted", key);
+            assert!(History::open(key).is_err(), "{:?} is opened", key);
+        }
+    }
+}
@@ -46,18 +46,35 @@
 #![warn(clippy::useless_let_if_seq)]
 #![allow(clippy::missing_errors_doc)]
 
+use structopt::StructOpt as _;
 use tracing_subscriber::EnvFilter;
 
+pub mod auth;
+pub mod cli;
+pub mod history;
+pub mod matching;
+pub mod queue;
 pub mod server;
 pub mod socket_traits;
+pub mod systemd;
 pub mod types;
 pub mod ui;
+pub mod unix_socket;
+pub mod util;
 
 fn main() -> color_eyre::eyre::Result<()> {
     color_eyre::install()?;
+    let opts = cli::Opts::from_args();
     tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::from_default_env())
         .init();
-    server::Server::run_tcp("127.0.0.1:5555")?;
+    let activated = systemd::activated_listeners()?;
+    let addresses = opts.addresses(activated.is_empty())?;
+    let config = server::Config {
+        token: opts.token()?,
+        allowed_uids: opts.allow_uid.clone(),
+        multi_window: opts.multi_window,
+    };
+    server::Server::run_many(&addresses, activated, config)?;
     Ok(())
 }
@@ -0,0 +1,596 @@
+use std::{
+    collections::HashSet,
+    convert::TryFrom,
+    iter,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use crossbeam::channel::{self, Receiver, Sender};
+use druid::{im, ExtEventSink, Selector, Target};
+use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher as _};
+use nix::unistd::{sysconf, SysconfVar};
+use regex::{Regex, RegexBuilder};
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization as _};
+
+use crate::types::{Case, Choice, Matcher, Query, Ranking};
+
+use self::extended::Extended;
+
+mod extended;
+
+/// Result of a job, sent to the window that submitted it
+pub const MATCHED_SELECTOR: Selector<Matched> = Selector::new("Matched");
+
+/// Choices scored between checks for cancellation
+const CANCEL_CHECK_INTERVAL: usize = 1024;
+
+/// How a text matched the input
+#[derive(Debug)]
+pub struct Hit {
+    /// Higher is better
+    pub score: i64,
+    /// Indices of matched characters, in ascending order
+    pub positions: Vec<usize>,
+}
+
+/// Matcher prepared to score choices against an input
+pub struct Scorer {
+    pattern: Pattern,
+    /// Compare texts in NFKD without combining marks
+    normalize: bool,
+    /// Compare lowercase texts, as case doesn't matter and the pattern is lowercase
+    lowercase: bool,
+}
+
+/// Input prepared for one of the matchers
+enum Pattern {
+    /// Matches nothing, for matchers that don't score and invalid patterns
+    Nothing,
+    Fuzzy(SkimMatcherV2, String),
+    Exact(String),
+    Prefix(String),
+    Substring(String),
+    Regex(Regex),
+    /// Input characters, lowercase unless case matters
+    Initials(Vec<char>, bool),
+    Extended(SkimMatcherV2, Extended),
+}
+
+impl Scorer {
+    /// Prepare to score choices against the query
+    ///
+    /// # Errors
+    ///
+    /// Fails if the input is an invalid regular expression for `Matcher::Regex`.
+    pub fn new(query: Query<'_>) -> Result<Self, regex::Error> {
+        let case = query.case.unwrap_or_else(|| query.matcher.default_case());
+        let case_sensitive = case.is_sensitive(query.input);
+        let input = |lowercase| fold(query.input, query.normalize, lowercase).0;
+        let pattern = match query.matcher {
+            Matcher::None => Pattern::Nothing,
+            Matcher::Fuzzy => Pattern::Fuzzy(fuzzy_matcher(case), input(false)),
+            Matcher::Exact => Pattern::Exact(input(!case_sensitive)),
+            Matcher::Prefix => Pattern::Prefix(input(!case_sensitive)),
+            Matcher::Substring => Pattern::Substring(input(!case_sensitive)),
+            Matcher::Regex => Pattern::Regex(
+                RegexBuilder::new(&input(false))
+                    .case_insensitive(!case_sensitive)
+                    .build()?,
+            ),
+            Matcher::Initials => {
+                Pattern::Initials(input(!case_sensitive).chars().collect(), case_sensitive)
+            }
+            Matcher::Extended => {
+                Pattern::Extended(fuzzy_matcher(case), Extended::parse(&input(false), case))
+            }
+        };
+        let lowercase = !case_sensitive
+            && matches!(
+                query.matcher,
+                Matcher::Exact | Matcher::Prefix | Matcher::Substring
+            );
+
+        Ok(Scorer {
+            pattern,
+            normalize: query.normalize,
+            lowercase,
+        })
+    }
+
+    /// Prepare to score choices against the query, matching nothing if it's invalid
+    #[must_use]
+    pub fn new_or_invalid(query: Query<'_>) -> Self {
+        Self::new(query).unwrap_or_else(|_| Self::invalid())
+    }
+
+    /// Scorer matching nothing, used in place of an invalid one
+    #[must_use]
+    pub fn invalid() -> Self {
+        Scorer {
+            pattern: Pattern::Nothing,
+            normalize: false,
+            lowercase: false,
+        }
+    }
+
+    /// Score of a text along with the matched characters; `None` if it doesn't match
+    #[must_use]
+    pub fn score(&self, text: &str) -> Option<Hit> {
+        if !self.normalize && !self.lowercase {
+            return self.pattern.score(text);
+        }
+
+        let (folded, origins) = fold(text, self.normalize, self.lowercase);
+        let mut hit = self.pattern.score(&folded)?;
+        hit.positions = to_origins(&hit.positions, &origins);
+        Some(hit)
+    }
+}
+
+impl Pattern {
+    fn score(&self, text: &str) -> Option<Hit> {
+        match self {
+            Pattern::Nothing => None,
+            Pattern::Fuzzy(matcher, input) => matcher
+                .fuzzy_indices(text, input)
+                .map(|(score, positions)| Hit { score, positions }),
+            Pattern::Exact(input) => (text == input).then(|| Hit {
+                score: 0,
+                positions: char_span(text, 0, text.len()),
+            }),
+            // Shorter texts are closer to the input
+            Pattern::Prefix(input) => text.strip_prefix(input.as_str()).map(|rest| Hit {
+                score: penalty(rest.len()),
+                positions: char_span(text, 0, input.len()),
+            }),
+            // Earlier matches go first
+            Pattern::Substring(input) => text.find(input.as_str()).map(|start| Hit {
+                score: penalty(start),
+                positions: char_span(text, start, start + input.len()),
+            }),
+            Pattern::Regex(regex) => regex.find(text).map(|found| Hit {
+                score: penalty(found.start()),
+                positions: char_span(text, found.start(), found.end()),
+            }),
+            Pattern::Initials(input, case_sensitive) => {
+                initials_score(text, input, *case_sensitive)
+            }
+            Pattern::Extended(matcher, query) => query.score(matcher, text),
+        }
+    }
+}
+
+fn fuzzy_matcher(case: Case) -> SkimMatcherV2 {
+    let matcher = SkimMatcherV2::default();
+    match case {
+        Case::Smart => matcher.smart_case(),
+        Case::Ignore => matcher.ignore_case(),
+        Case::Respect => matcher.respect_case(),
+    }
+}
+
+/// Positions in a folded text mapped to characters of the original one
+fn to_origins(positions: &[usize], origins: &[usize]) -> Vec<usize> {
+    let mut mapped: Vec<_> = positions
+        .iter()
+        .filter_map(|&pos| origins.get(pos).copied())
+        .collect();
+    mapped.dedup();
+    mapped
+}
+
+/// Text as compared by the scorer, along with the index of the original character
+/// each of its characters comes from
+fn fold(text: &str, normalize: bool, lowercase: bool) -> (String, Vec<usize>) {
+    let mut folded = String::with_capacity(text.len());
+    let mut origins = Vec::with_capacity(text.len());
+    for (idx, ch) in text.chars().enumerate() {
+        let mut push = |ch: char| {
+            folded.push(ch);
+            origins.push(idx);
+        };
+        let mut push_folded = |ch: char| {
+            if lowercase {
+                ch.to_lowercase().for_each(&mut push);
+            } else {
+                push(ch);
+            }
+        };
+
+        if normalize {
+            iter::once(ch)
+                .nfkd()
+                .filter(|&part| !is_combining_mark(part))
+                .for_each(&mut push_folded);
+        } else {
+            push_folded(ch);
+        }
+    }
+    (folded, origins)
+}
+
+fn penalty(distance: usize) -> i64 {
+    -i64::try_from(distance).unwrap_or(i64::MAX)
+}
+
+/// Indices of characters in a byte range of the text
+fn char_span(text: &str, start: usize, end: usize) -> Vec<usize> {
+    text.char_indices()
+        .enumerate()
+        .filter(|(_, (byte, _))| (start..end).contains(byte))
+        .map(|(idx, _)| idx)
+        .collect()
+}
+
+/// Character of a text being matched against initials
+struct Letter {
+    ch: char,
+    /// Word the character belongs to, `None` for separators
+    word: Option<usize>,
+    is_initial: bool,
+}
+
+/// Number of input characters matching initials of words, with the rest matching letters
+/// following them, so "gco" matches "git checkout"
+///
+/// Words start after non-alphanumeric characters and at uppercase letters following lowercase ones.
+/// Input characters must be lowercase unless `case_sensitive` is set.
+fn initials_score(text: &str, input: &[char], case_sensitive: bool) -> Option<Hit> {
+    let mut letters = Vec::new();
+    let mut word = 0_usize;
+    let mut prev: Option<char> = None;
+    for ch in text.chars() {
+        let is_initial = ch.is_alphanumeric()
+            && prev.map_or(true, |prev| {
+                !prev.is_alphanumeric() || (prev.is_lowercase() && ch.is_uppercase())
+            });
+        if is_initial {
+            word += 1;
+        }
+        letters.push(Letter {
+            ch,
+            word: Some(word).filter(|_| ch.is_alphanumeric()),
+            is_initial,
+        });
+        prev = Some(ch);
+    }
+
+    // For every input character and every letter: best score of the input so far
+    // with this character matched at this letter, and the letter of the previous one
+    let mut rows: Vec<Vec<Option<(i64, Option<usize>)>>> = Vec::with_capacity(input.len());
+    for (idx, &wanted) in input.iter().enumerate() {
+        let prev_row = rows.last();
+        let mut best_before = None;
+        let mut best_in_word = None;
+        let mut current_word = None;
+        let mut row = Vec::with_capacity(letters.len());
+
+        for (pos, letter) in letters.iter().enumerate() {
+            if letter.word != current_word {
+                current_word = letter.word;
+                best_in_word = None;
+            }
+
+            let same = if case_sensitive {
+                letter.ch == wanted
+            } else {
+                letter.ch.to_lowercase().eq(iter::once(wanted))
+            };
+            let matched = if !same {
+                None
+            } else if letter.is_initial && idx == 0 {
+                Some((1, None))
+            } else if letter.is_initial {
+                best_before.map(|(score, prev)| (score + 1, Some(prev)))
+            } else if current_word.is_some() {
+                best_in_word.map(|(score, prev)| (score, Some(prev)))
+            } else {
+                None
+            };
+            row.push(matched);
+
+            let prev_best = prev_row
+                .and_then(|prev_row| prev_row.get(pos).copied().flatten())
+                .map(|(score, _)| (score, pos));
+            best_before = better(best_before, prev_best);
+            if current_word.is_some() {
+                best_in_word = better(best_in_word, prev_best);
+            }
+        }
+        rows.push(row);
+    }
+
+    let (score, last) = rows
+        .last()?
+        .iter()
+        .enumerate()
+        .map(|(pos, cell)| cell.map(|(score, _)| (score, pos)))
+        .fold(None, better)?;
+
+    let mut positions = Vec::with_capacity(input.len());
+    let mut pos = Some(last);
+    for row in rows.iter().rev() {
+        let here = pos?;
+        positions.push(here);
+        pos = row.get(here).copied().flatten().and_then(|(_, prev)| prev);
+    }
+    positions.reverse();
+
+    Some(Hit { score, positions })
+}
+
+/// Candidate with the higher score, the earlier one on ties
+fn better(current: Option<(i64, usize)>, candidate: Option<(i64, usize)>) -> Option<(i64, usize)> {
+    match (current, candidate) {
+        (Some((score, _)), Some((new_score, _))) if new_score <= score => current,
+        _ => candidate.or(current),
+    }
+}
+
+/// Choices to rescore against a new input
+pub struct Job {
+    pub client_id: usize,
+    pub target: Target,
+    pub input: String,
+    pub matcher: Matcher,
+    pub ranking: Ranking,
+    pub filter: bool,
+    pub case: Option<Case>,
+    pub normalize: bool,
+    pub options: im::OrdSet<Choice>,
+    pub hidden: im::OrdSet<Choice>,
+    /// Ids of choices not matching a prefix of `input`, which can't match it either
+    pub rejected: Option<Arc<HashSet<usize>>>,
+}
+
+impl Job {
+    fn query(&self) -> Query<'_> {
+        Query {
+            input: &self.input,
+            matcher: self.matcher,
+            ranking: self.ranking,
+            filter: self.filter,
+            case: self.case,
+            normalize: self.normalize,
+        }
+    }
+}
+
+/// Submitted job, which can be cancelled once its result is no longer needed
+#[derive(Debug, Clone)]
+pub struct Ticket {
+    pub generation: u64,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Ticket {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug)]
+pub struct Matched {
+    pub client_id: usize,
+    /// `generation` of the job's ticket
+    pub generation: u64,
+    pub input: String,
+    pub options: im::OrdSet<Choice>,
+    /// Choices filtered out for not matching `input`
+    pub hidden: im::OrdSet<Choice>,
+    /// Ids of choices not matching `input`
+    pub rejected: Arc<HashSet<usize>>,
+    /// Why the input couldn't be used, if it's invalid
+    pub error: Option<String>,
+}
+
+/// Way to reach the matching thread
+#[derive(Debug, Clone)]
+pub struct Engine {
+    jobs: Sender<(Job, Ticket)>,
+    last_generation: Arc<AtomicU64>,
+}
+
+impl Engine {
+    /// Start the matching thread, sending results through `sink`
+    #[must_use]
+    pub fn start(sink: ExtEventSink) -> Self {
+        let (jobs, receiver) = channel::unbounded();
+        thread::spawn(move || run_jobs(&receiver, &sink));
+        Engine {
+            jobs,
+            last_generation: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn submit(&self, job: Job) -> Ticket {
+        let ticket = Ticket {
+            generation: self.last_generation.fetch_add(1, Ordering::Relaxed) + 1,
+            cancelled: Arc::default(),
+        };
+        if self.jobs.send((job, ticket.clone())).is_err() {
+            tracing::error!("matching thread stopped");
+        }
+        ticket
+    }
+}
+
+fn run_jobs(jobs: &Receiver<(Job, Ticket)>, sink: &ExtEventSink) {
+    for (job, ticket) in jobs {
+        if ticket.is_cancelled() {
+            continue;
+        }
+
+        let (scorer, error) = match Scorer::new(job.query()) {
+            Ok(scorer) => (scorer, None),
+            Err(err) => (Scorer::invalid(), Some(err.to_string())),
+        };
+        let (options, hidden, rejected) = match score(&job, &scorer, &ticket) {
+            Some(scored) => scored,
+            None => {
+                tracing::debug!(generation = ticket.generation, "matching cancelled");
+                continue;
+            }
+        };
+
+        let matched = Matched {
+            client_id: job.client_id,
+            generation: ticket.generation,
+            input: job.input,
+            options,
+            hidden,
+            rejected: Arc::new(rejected),
+            error,
+        };
+        if let Err(err) = sink.submit_command(MATCHED_SELECTOR, matched, job.target) {
+            tracing::error!("application stopped: {}", err);
+            return;
+        }
+    }
+}
+
+/// Number of cores to score on, at least one
+fn online_cpus() -> usize {
+    sysconf(SysconfVar::_NPROCESSORS_ONLN)
+        .ok()
+        .flatten()
+        .and_then(|count| usize::try_from(count).ok())
+        .filter(|&count| count > 0)
+        .unwrap_or(1)
+}
+
+/// Shown and hidden choices along with ids of the rejected ones
+type Scored = (im::OrdSet<Choice>, im::OrdSet<Choice>, HashSet<usize>);
+
+/// Score every choice, splitting them between all cores; `None` if cancelled
+fn score(job: &Job, scorer: &Scorer, ticket: &Ticket) -> Option<Scored> {
+    let options: Vec<_> = job.options.iter().chain(&job.hidden).cloned().collect();
+    let threads = online_cpus();
+    let chunk_size = ((options.len() + threads - 1) / threads).max(1);
+
+    let chunks = crossbeam::scope(|scope| {
+        let handles: Vec<_> = options
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(move |_| score_chunk(chunk, job, scorer, ticket)))
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().ok().flatten())
+            .collect::<Option<Vec<_>>>()
+    })
+    .ok()
+    .flatten()?;
+
+    let query = job.query();
+    let mut shown = im::OrdSet::new();
+    let mut hidden = im::OrdSet::new();
+    let mut rejected = HashSet::new();
+    for (chunk_scored, chunk_rejected) in chunks {
+        for option in chunk_scored {
+            if query.hides(&option) {
+                hidden.insert(option);
+            } else {
+                shown.insert(option);
+            }
+        }
+        rejected.extend(chunk_rejected);
+    }
+    Some((shown, hidden, rejected))
+}
+
+fn score_chunk(
+    chunk: &[Choice],
+    job: &Job,
+    scorer: &Scorer,
+    ticket: &Ticket,
+) -> Option<(Vec<Choice>, Vec<usize>)> {
+    let query = job.query();
+    let mut scored = Vec::with_capacity(chunk.len());
+    let mut rejected = Vec::new();
+
+    for (idx, option) in chunk.iter().enumerate() {
+        if idx % CANCEL_CHECK_INTERVAL == 0 && ticket.is_cancelled() {
+            return None;
+        }
+
+        let mut option = option.clone();
+        let known_rejected = job
+            .rejected
+            .as_ref()
+            .map_or(false, |rejected| rejected.contains(&option.id));
+        if known_rejected {
+            option.set_hit(None, query);
+        } else {
+            option.match_query(scorer, query);
+        }
+
+        // Nothing is scored without input, but nothing is rejected either
+        if option.score.is_none() && !job.input.is_empty() {
+            rejected.push(option.id);
+        }
+        scored.push(option);
+    }
+
+    Some((scored, rejected))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn initials(text: &str, input: &str) -> Option<(i64, Vec<usize>)> {
+        let input: Vec<char> = input.chars().collect();
+        initials_score(text, &input, false).map(|hit| (hit.score, hit.positions))
+    }
+
+    #[test]
+    fn matches_initials_of_words() {
+        assert_eq!(initials("git checkout", "gc"), Some((2, vec![0, 4])));
+        assert_eq!(initials("foo-bar_baz", "fbb"), Some((3, vec![0, 4, 8])));
+    }
+
+    #[test]
+    fn matches_letters_following_initials() {
+        assert_eq!(initials("git checkout", "gco"), Some((2, vec![0, 4, 9])));
+        assert_eq!(
+            initials("git checkout", "gich"),
+            Some((2, vec![0, 1, 4, 5]))
+        );
+    }
+
+    #[test]
+    fn splits_camel_case() {
+        assert_eq!(initials("fooBar", "fb"), Some((2, vec![0, 3])));
+    }
+
+    #[test]
+    fn prefers_more_initials() {
+        // "c" could match inside "checkout", but the initial of "commit" scores higher
+        assert_eq!(
+            initials("git checkout commit", "gcc"),
+            Some((3, vec![0, 4, 13]))
+        );
+    }
+
+    #[test]
+    fn rejects_non_initials() {
+        assert_eq!(initials("git checkout", "it"), None);
+        assert_eq!(initials("git checkout", "xyz"), None);
+        assert_eq!(initials("git checkout", "cg"), None);
+    }
+
+    #[test]
+    fn respects_case_if_asked() {
+        assert!(initials_score("fooBar", &['f', 'B'], true).is_some());
+        assert!(initials_score("fooBar", &['f', 'b'], true).is_none());
+        assert!(initials_score("fooBar", &['f', 'b'], false).is_some());
+    }
+}
@@ -0,0 +1,288 @@
+use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher as _};
+
+use super::{char_span, fold, to_origins, Hit};
+use crate::types::Case;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Fuzzy,
+    /// `'term`, or any negated term without other markers
+    Substring,
+    /// `^term`
+    Prefix,
+    /// `term$`
+    Suffix,
+    /// `^term$`
+    Exact,
+}
+
+#[derive(Debug)]
+struct Term {
+    kind: Kind,
+    text: String,
+    /// `!term`, excluding choices it matches
+    negated: bool,
+    /// Case matters for the term; otherwise it's lowercase already
+    case_sensitive: bool,
+}
+
+impl Term {
+    fn parse(token: &str, case: Case) -> Option<Self> {
+        let (negated, token) = match token.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, token),
+        };
+
+        let (kind, text) = if let Some(rest) = token.strip_prefix('\'') {
+            (Kind::Substring, rest)
+        } else {
+            let prefix = token.strip_prefix('^');
+            let body = prefix.unwrap_or(token);
+            let suffix = body.strip_suffix('$').filter(|rest| !rest.is_empty());
+            match (prefix.is_some(), suffix) {
+                (true, Some(rest)) => (Kind::Exact, rest),
+                (true, None) => (Kind::Prefix, body),
+                (false, Some(rest)) => (Kind::Suffix, rest),
+                (false, None) if negated => (Kind::Substring, body),
+                (false, None) => (Kind::Fuzzy, body),
+            }
+        };
+
+        if text.is_empty() {
+            return None;
+        }
+
+        let case_sensitive = case.is_sensitive(text);
+        Some(Term {
+            kind,
+            text: if case_sensitive {
+                text.to_owned()
+            } else {
+                text.to_lowercase()
+            },
+            negated,
+            case_sensitive,
+        })
+    }
+
+    /// Score of the term against a text, `lower` being its lowercase version along with
+    /// the index of the original character each of its characters comes from
+    fn score(
+        &self,
+        matcher: &SkimMatcherV2,
+        text: &str,
+        lower: &(String, Vec<usize>),
+    ) -> Option<Hit> {
+        let (haystack, origins) = if self.case_sensitive {
+            (text, None)
+        } else {
+            (lower.0.as_str(), Some(&lower.1))
+        };
+        let needle = self.text.as_str();
+        let start = match self.kind {
+            Kind::Fuzzy => {
+                return matcher
+                    .fuzzy_indices(text, needle)
+                    .map(|(score, positions)| Hit { score, positions })
+            }
+            Kind::Substring => haystack.find(needle),
+            Kind::Prefix => haystack.starts_with(needle).then(|| 0),
+            Kind::Suffix => haystack
+                .ends_with(needle)
+                .then(|| haystack.len() - needle.len()),
+            Kind::Exact => (haystack == needle).then(|| 0),
+        };
+
+        match (start, self.negated) {
+            (None, true) => Some(Hit {
+                score: 0,
+                positions: Vec::new(),
+            }),
+            (Some(start), false) => {
+                // Lowercasing may change the number of characters, as in "İ"
+                let positions = char_span(haystack, start, start + needle.len());
+                Some(Hit {
+                    // Non-fuzzy terms are still ranked by how well they match fuzzily
+                    score: matcher.fuzzy_match(text, needle).unwrap_or(0),
+                    positions: match origins {
+                        Some(origins) => to_origins(&positions, origins),
+                        None => positions,
+                    },
+                })
+            }
+            (Some(_), true) | (None, false) => None,
+        }
+    }
+}
+
+/// Query in fzf's extended syntax
+///
+/// Space-separated terms must all match; terms joined with `|` match if any of them does.
+#[derive(Debug)]
+pub struct Extended {
+    /// Groups of alternatives, all of which must match
+    groups: Vec<Vec<Term>>,
+}
+
+impl Extended {
+    /// Parse the input, deciding whether case matters for each term separately
+    #[must_use]
+    pub fn parse(input: &str, case: Case) -> Self {
+        let mut groups: Vec<Vec<Term>> = Vec::new();
+        let mut join_next = false;
+        for token in input.split_whitespace() {
+            if token == "|" {
+                join_next = !groups.is_empty();
+                continue;
+            }
+
+            let term = match Term::parse(token, case) {
+                Some(term) => term,
+                None => continue,
+            };
+            match groups.last_mut() {
+                Some(group) if join_next => group.push(term),
+                _ => groups.push(vec![term]),
+            }
+            join_next = false;
+        }
+        Extended { groups }
+    }
+
+    /// Sum of scores of every group, taking the best alternative in each
+    #[must_use]
+    pub fn score(&self, matcher: &SkimMatcherV2, text: &str) -> Option<Hit> {
+        let lower = fold(text, false, true);
+        let mut total = Hit {
+            score: 0,
+            positions: Vec::new(),
+        };
+        for group in &self.groups {
+            let best = group
+                .iter()
+                .filter_map(|term| term.score(matcher, text, &lower))
+                .max_by_key(|hit| hit.score)?;
+            total.score = total.score.saturating_add(best.score);
+            total.positions.extend(best.positions);
+        }
+
+        total.positions.sort_unstable();
+        total.positions.dedup();
+        Some(total)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(input: &str) -> Vec<Vec<(Kind, &str, bool)>> {
+        Extended::parse(input, Case::Smart)
+            .groups
+            .iter()
+            .map(|group| {
+                group
+                    .iter()
+                    .map(|term| (term.kind, term.text.as_str(), term.negated))
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn matches(input: &str, text: &str) -> bool {
+        Extended::parse(input, Case::Smart)
+            .score(&SkimMatcherV2::default(), text)
+            .is_some()
+    }
+
+    #[test]
+    fn parses_operators() {
+        assert_eq!(parsed("foo"), vec![vec![(Kind::Fuzzy, "foo", false)]]);
+        assert_eq!(parsed("'foo"), vec![vec![(Kind::Substring, "foo", false)]]);
+        assert_eq!(parsed("^foo"), vec![vec![(Kind::Prefix, "foo", false)]]);
+        assert_eq!(parsed("foo$"), vec![vec![(Kind::Suffix, "foo", false)]]);
+        assert_eq!(parsed("^foo$"), vec![vec![(Kind::Exact, "foo", false)]]);
+    }
+
+    #[test]
+    fn parses_negation() {
+        assert_eq!(parsed("!foo"), vec![vec![(Kind::Substring, "foo", true)]]);
+        assert_eq!(parsed("!'foo"), vec![vec![(Kind::Substring, "foo", true)]]);
+        assert_eq!(parsed("!^foo"), vec![vec![(Kind::Prefix, "foo", true)]]);
+        assert_eq!(parsed("!foo$"), vec![vec![(Kind::Suffix, "foo", true)]]);
+    }
+
+    #[test]
+    fn parses_alternation() {
+        assert_eq!(
+            parsed("a | b c"),
+            vec![
+                vec![(Kind::Fuzzy, "a", false), (Kind::Fuzzy, "b", false)],
+                vec![(Kind::Fuzzy, "c", false)],
+            ]
+        );
+        assert_eq!(parsed("| a"), vec![vec![(Kind::Fuzzy, "a", false)]]);
+        assert_eq!(parsed("a |"), vec![vec![(Kind::Fuzzy, "a", false)]]);
+        assert_eq!(
+            parsed("a | | b"),
+            vec![vec![(Kind::Fuzzy, "a", false), (Kind::Fuzzy, "b", false)]]
+        );
+    }
+
+    #[test]
+    fn skips_empty_terms() {
+        assert!(parsed("").is_empty());
+        assert!(parsed("   ").is_empty());
+        assert!(parsed("!").is_empty());
+        assert!(parsed("'").is_empty());
+        assert!(parsed("^").is_empty());
+        assert!(parsed("!'").is_empty());
+        assert_eq!(parsed("! foo"), vec![vec![(Kind::Fuzzy, "foo", false)]]);
+        // A lone `$` has nothing before it to anchor, so it's a plain term
+        assert_eq!(parsed("$"), vec![vec![(Kind::Fuzzy, "$", false)]]);
+    }
+
+    #[test]
+    fn decides_case_per_term() {
+        let query = Extended::parse("Foo bar", Case::Smart);
+        let terms: Vec<_> = query.groups.iter().flatten().collect();
+        assert_eq!(terms.len(), 2);
+        assert!(terms
+            .iter()
+            .any(|term| term.text == "Foo" && term.case_sensitive));
+        assert!(terms
+            .iter()
+            .any(|term| term.text == "bar" && !term.case_sensitive));
+
+        let query = Extended::parse("Foo", Case::Ignore);
+        let term = query
+            .groups
+            .iter()
+            .flatten()
+            .next()
+            .expect("term is parsed");
+        assert_eq!(term.text, "foo");
+        assert!(!term.case_sensitive);
+    }
+
+    #[test]
+    fn scores_terms() {
+        assert!(matches("^git !push", "git commit"));
+        assert!(!matches("^git !push", "git push"));
+        assert!(matches("'commit", "git commit"));
+        assert!(!matches("'cmt", "git commit"));
+        assert!(matches("mit$", "git commit"));
+        assert!(!matches("^commit$", "git commit"));
+        assert!(matches("push | commit", "git commit"));
+        assert!(!matches("push | pull", "git commit"));
+        assert!(matches("", "anything"));
+    }
+
+    #[test]
+    fn highlights_original_characters() {
+        let hit = Extended::parse("'b", Case::Smart)
+            .score(&SkimMatcherV2::default(), "İb")
+            .expect("term matches");
+        assert_eq!(hit.positions, vec![1]);
+    }
+}
@@ -0,0 +1,253 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use crossbeam::channel::{self, Receiver, Sender};
+use parking_lot::Mutex;
+
+/// Change of a client's place in the queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Update {
+    /// Number of clients ahead, including the active one
+    Position(usize),
+    /// The client may use the UI now
+    Granted,
+    /// A client with higher priority took over the UI; wait for `Granted` again
+    Suspended,
+}
+
+struct Entry {
+    client_id: usize,
+    priority: i64,
+    updates: Sender<Update>,
+}
+
+#[derive(Default)]
+struct Inner {
+    active: Option<Entry>,
+    /// Ordered by priority, highest first
+    waiting: VecDeque<Entry>,
+}
+
+/// Priority queue of clients waiting for the UI
+///
+/// Clients with the same priority are served in FIFO order. A client with higher
+/// priority than the active one suspends it and takes over immediately.
+#[derive(Default)]
+pub struct Queue {
+    /// Only one client may use the UI at a time; otherwise everyone is granted immediately
+    exclusive: bool,
+    inner: Mutex<Inner>,
+}
+
+/// Client's place in the queue, left on drop
+pub struct Ticket {
+    queue: Arc<Queue>,
+    client_id: usize,
+    updates: Receiver<Update>,
+}
+
+impl Ticket {
+    /// Updates of this client's state in the queue
+    #[must_use]
+    pub fn updates(&self) -> &Receiver<Update> {
+        &self.updates
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        self.queue.leave(self.client_id);
+    }
+}
+
+impl Inner {
+    /// Notify waiters starting with `from` about their new positions
+    fn renumber(&self, from: usize) {
+        for (idx, entry) in self.waiting.iter().enumerate().skip(from) {
+            entry.updates.send(Update::Position(idx + 1)).ok();
+        }
+    }
+
+    /// Put `entry` in the waiting list, before or after clients with the same priority
+    fn wait(&mut self, entry: Entry, before_equal: bool) {
+        let idx = self
+            .waiting
+            .iter()
+            .position(|other| {
+                other.priority < entry.priority
+                    || (before_equal && other.priority == entry.priority)
+            })
+            .unwrap_or(self.waiting.len());
+        self.waiting.insert(idx, entry);
+        self.renumber(idx);
+    }
+
+    fn grant(&mut self, entry: Entry) {
+        entry.updates.send(Update::Granted).ok();
+        self.active = Some(entry);
+    }
+
+    /// Pass the UI to the first waiter that is still listening
+    fn advance(&mut self) {
+        self.active = None;
+        while let Some(entry) = self.waiting.pop_front() {
+            if entry.updates.send(Update::Granted).is_ok() {
+                self.active = Some(entry);
+                break;
+            }
+        }
+        self.renumber(0);
+    }
+}
+
+impl Queue {
+    #[must_use]
+    pub fn new(exclusive: bool) -> Arc<Self> {
+        Arc::new(Self {
+            exclusive,
+            inner: Mutex::default(),
+        })
+    }
+
+    /// Take a place in the queue
+    ///
+    /// If the UI is busy with a client of the same or higher priority and `wait` is not set,
+    /// returns `None`. Non-exclusive queue grants the UI to every client right away.
+    pub fn join(self: &Arc<Self>, client_id: usize, priority: i64, wait: bool) -> Option<Ticket> {
+        let (sender, receiver) = channel::unbounded();
+        if self.exclusive {
+            let mut inner = self.inner.lock();
+            let entry = Entry {
+                client_id,
+                priority,
+                updates: sender,
+            };
+
+            match inner.active.take() {
+                None => inner.grant(entry),
+                Some(active) if active.priority < priority => {
+                    active.updates.send(Update::Suspended).ok();
+                    inner.wait(active, true);
+                    inner.grant(entry);
+                }
+                Some(active) => {
+                    inner.active = Some(active);
+                    if !wait {
+                        return None;
+                    }
+                    inner.wait(entry, false);
+                }
+            }
+        } else {
+            sender.send(Update::Granted).ok();
+        }
+
+        Some(Ticket {
+            queue: Arc::clone(self),
+            client_id,
+            updates: receiver,
+        })
+    }
+
+    fn leave(&self, client_id: usize) {
+        let mut inner = self.inner.lock();
+        if inner
+            .active
+            .as_ref()
+            .map_or(false, |active| active.client_id == client_id)
+        {
+            inner.advance();
+        } else if let Some(idx) = inner
+            .waiting
+            .iter()
+            .position(|entry| entry.client_id == client_id)
+        {
+            inner.waiting.remove(idx);
+            inner.renumber(idx);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn join(queue: &Arc<Queue>, client_id: usize, priority: i64) -> Ticket {
+        queue
+            .join(client_id, priority, true)
+            .expect("waiting client joins")
+    }
+
+    /// Updates received since the last call
+    fn updates(ticket: &Ticket) -> Vec<Update> {
+        ticket.updates().try_iter().collect()
+    }
+
+    #[test]
+    fn serves_equal_priorities_in_order() {
+        let queue = Queue::new(true);
+        let first = join(&queue, 1, 0);
+        let second = join(&queue, 2, 0);
+        let third = join(&queue, 3, 0);
+        assert_eq!(updates(&first), [Update::Granted]);
+        assert_eq!(updates(&second), [Update::Position(1)]);
+        assert_eq!(updates(&third), [Update::Position(2)]);
+
+        drop(first);
+        assert_eq!(updates(&second), [Update::Granted]);
+        assert_eq!(updates(&third), [Update::Position(1)]);
+
+        drop(second);
+        assert_eq!(updates(&third), [Update::Granted]);
+    }
+
+    #[test]
+    fn renumbers_clients_behind_the_leaving_one() {
+        let queue = Queue::new(true);
+        let _active = join(&queue, 1, 0);
+        let second = join(&queue, 2, 0);
+        let third = join(&queue, 3, 0);
+        let fourth = join(&queue, 4, 0);
+        updates(&second);
+        updates(&fourth);
+
+        drop(third);
+        assert!(updates(&second).is_empty());
+        assert_eq!(updates(&fourth), [Update::Position(2)]);
+    }
+
+    #[test]
+    fn rejects_clients_that_would_wait() {
+        let queue = Queue::new(true);
+        let _active = join(&queue, 1, 0);
+        assert!(queue.join(2, 0, false).is_none());
+        assert!(queue.join(3, -1, false).is_none());
+        assert!(queue.join(4, 1, false).is_some());
+    }
+
+    #[test]
+    fn requeues_suspended_client_ahead_of_equals() {
+        let queue = Queue::new(true);
+        let first = join(&queue, 1, 0);
+        let second = join(&queue, 2, 0);
+        updates(&first);
+        updates(&second);
+
+        let urgent = join(&queue, 3, 1);
+        assert_eq!(updates(&urgent), [Update::Granted]);
+        assert_eq!(updates(&first), [Update::Suspended, Update::Position(1)]);
+        assert_eq!(updates(&second), [Update::Position(2)]);
+
+        drop(urgent);
+        assert_eq!(updates(&first), [Update::Granted]);
+        assert_eq!(updates(&second), [Update::Position(1)]);
+    }
+
+    #[test]
+    fn grants_everyone_when_not_exclusive() {
+        let queue = Queue::new(false);
+        let first = join(&queue, 1, 0);
+        let second = join(&queue, 2, 0);
+        assert_eq!(updates(&first), [Update::Granted]);
+        assert_eq!(updates(&second), [Update::Granted]);
+    }
+}
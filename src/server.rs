@@ -1,47 +1,118 @@
 use std::{
+    collections::HashMap,
     convert::Infallible,
+    fmt,
     io::{self, BufRead, BufReader, Read, Write},
-    net::{TcpListener, ToSocketAddrs},
-    os::unix::net::UnixListener,
-    path::Path,
+    net::TcpListener,
+    path::PathBuf,
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         Arc,
     },
-    thread,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
-use color_eyre::eyre::{self, bail, eyre, WrapErr as _};
+use color_eyre::eyre::{self, bail, WrapErr as _};
 use crossbeam::channel::{self, Receiver, Sender};
-use druid::Target;
 use enumflags2::BitFlags;
+use nix::unistd::geteuid;
 use parking_lot::Mutex;
 use serde::Serialize;
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::Signals,
+};
 
 use crate::{
-    socket_traits::{Listener, NetStream},
-    types::{
-        ClientRequest, Event, Registration, ServerEvent, Subscription, CLIENT_REQUEST_SELECTOR,
-    },
+    auth::Token,
+    history::{Bonuses, History},
+    queue::{self, Queue, Ticket},
+    socket_traits::{Listener, NetStream, PeerCredentials},
+    systemd::ActivatedListener,
+    types::{ClientRequest, Event, Registration, ServerEvent, Subscription},
     ui::self,
+    unix_socket::{self, SocketFile},
 };
 
-pub const PROTOCOL_VERSION: u8 = 0;
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// How a waiting client is told about its place in the queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum QueueNotice {
+    /// `Queued` on every change of position
+    Positions,
+    /// A single `Busy` before `Registered`, as protocol 0 clients expect
+    Busy,
+    /// Nothing, for protocol 0 clients that are already registered
+    Silent,
+}
+
+#[derive(Debug, Clone)]
+pub enum Address {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Tcp(addr) => write!(f, "tcp:{}", addr),
+            Address::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+type SharedWrite = Arc<Mutex<dyn Write + Send>>;
+
+#[derive(Debug, Default)]
+pub struct Config {
+    /// Token TCP clients must present in their registration
+    pub token: Option<Token>,
+    /// Users besides the current one allowed to connect through Unix sockets
+    pub allowed_uids: Vec<u32>,
+    /// Give every client its own window instead of queueing them
+    pub multi_window: bool,
+}
+
+struct Client {
+    write: SharedWrite,
+    control: Option<ui::Handle>,
+    /// Taken when the window is first shown
+    accepted_at: Option<Instant>,
+}
 
 pub struct Server {
-    busy: Mutex<()>,
+    config: Config,
+    queue: Arc<Queue>,
     last_id: AtomicUsize,
+    ui_sender: Sender<ui::Request>,
+    clients: Mutex<HashMap<usize, Client>>,
+    socket_files: Mutex<Vec<SocketFile>>,
+    shutting_down: AtomicBool,
 }
 
 impl Server {
-    fn new() -> Arc<Self> {
+    /// Create a server and start its UI thread
+    ///
+    /// Clients from every listener attached to this server share the same UI.
+    #[must_use]
+    pub fn new(config: Config) -> Arc<Self> {
         Arc::new(Server {
-            busy: Mutex::new(()),
+            queue: Queue::new(!config.multi_window),
+            ui_sender: Self::start_ui(config.multi_window),
+            config,
             last_id: AtomicUsize::new(0),
+            clients: Mutex::new(HashMap::new()),
+            socket_files: Mutex::new(Vec::new()),
+            shutting_down: AtomicBool::new(false),
         })
     }
 
-    fn send_message<M: Serialize, W: Write>(mut write: &mut W, message: &M) -> eyre::Result<()> {
+    fn send_message<M: Serialize, W: Write + ?Sized>(
+        mut write: &mut W,
+        message: &M,
+    ) -> eyre::Result<()> {
         serde_json::to_writer(&mut write, message)?;
         writeln!(write)?;
         Ok(())
@@ -50,16 +121,64 @@ impl Server {
     fn send_events<W>(
         events: &Receiver<Event>,
         subscription: BitFlags<Subscription>,
+        debounce: Duration,
         write: &Mutex<W>,
+        history: Option<&History>,
+        protocol_version: u8,
     ) -> eyre::Result<Infallible>
     where
-        W: Write + Send,
+        W: Write + Send + ?Sized,
     {
+        let send = |event: Event| {
+            let event = ServerEvent::from(event).for_protocol(protocol_version);
+            Self::send_message(&mut *write.lock(), &event)
+        };
+
+        // Input waiting to be sent along with its deadline
+        let mut pending_input = None;
         loop {
-            let event = events.recv()?;
-            if event.needed(subscription) {
-                let message = ServerEvent::from(event);
-                Self::send_message(&mut *write.lock(), &message)?;
+            let event = if let Some((input, deadline)) = pending_input.take() {
+                match events.recv_deadline(deadline) {
+                    // Newer input replaces the pending one and restarts the timer
+                    Ok(newer @ Event::InputChange { .. }) => {
+                        pending_input = Some((newer, Instant::now() + debounce));
+                        continue;
+                    }
+                    // Pending input goes first, but only events the client receives hurry it
+                    Ok(event) if event.needed(subscription) => {
+                        send(input)?;
+                        event
+                    }
+                    Ok(event) => {
+                        pending_input = Some((input, deadline));
+                        event
+                    }
+                    Err(err) => {
+                        send(input)?;
+                        if err.is_disconnected() {
+                            return Err(err.into());
+                        }
+                        continue;
+                    }
+                }
+            } else {
+                events.recv()?
+            };
+
+            if let (Some(history), Event::Select(Some(choice))) = (history, &event) {
+                if let Err(err) = history.record(choice) {
+                    tracing::warn!("failed to record selection: {:#}", err);
+                }
+            }
+
+            if !event.needed(subscription) {
+                continue;
+            }
+
+            if matches!(event, Event::InputChange { .. }) && !debounce.is_zero() {
+                pending_input = Some((event, Instant::now() + debounce));
+            } else {
+                send(event)?;
             }
         }
     }
@@ -67,21 +186,118 @@ impl Server {
     fn serve_client<R, W>(
         self: Arc<Self>,
         read: R,
-        mut write: W,
+        write: W,
         client_id: usize,
-        ui_sender: &Sender<ui::InitialState>,
+        requires_token: bool,
+        accepted_at: Instant,
     ) -> eyre::Result<()>
     where
-        R: Read,
+        R: Read + Send + 'static,
         W: Write + Send + 'static,
     {
+        let write: SharedWrite = Arc::new(Mutex::new(write));
+        self.clients.lock().insert(
+            client_id,
+            Client {
+                write: Arc::clone(&write),
+                control: None,
+                accepted_at: Some(accepted_at),
+            },
+        );
+        let result = self.serve_connected(read, &write, client_id, requires_token);
+        self.clients.lock().remove(&client_id);
+        result
+    }
+
+    fn read_requests<R: Read>(
+        lines: io::Lines<BufReader<R>>,
+        requests: &Sender<ClientRequest>,
+        bonuses: &Bonuses,
+    ) {
+        for line in lines {
+            let line = match line {
+                Ok(line) => line,
+                Err(err) => {
+                    tracing::info!("failed to read client request: {}", err);
+                    break;
+                }
+            };
+
+            match serde_json::from_str(&line) {
+                Ok(mut req) => {
+                    bonuses.apply(&mut req);
+                    if requests.send(req).is_err() {
+                        break;
+                    }
+                }
+                Err(err) => {
+                    tracing::warn!(
+                        line = line.as_str(),
+                        "failed to parse client request: {}",
+                        err
+                    );
+                }
+            }
+        }
+    }
+
+    /// Wait for the UI, reporting queue position to the client
+    ///
+    /// Requests received meanwhile are stored in `pending`. Returns `false` if the client
+    /// cancelled the wait by sending `Stop` or disconnecting.
+    fn wait_in_queue(
+        ticket: &Ticket,
+        requests: &Receiver<ClientRequest>,
+        pending: &mut Vec<ClientRequest>,
+        write: &SharedWrite,
+        mut notice: QueueNotice,
+    ) -> eyre::Result<bool> {
+        loop {
+            channel::select! {
+                recv(ticket.updates()) -> update => match update? {
+                    queue::Update::Position(position) => match notice {
+                        QueueNotice::Positions => {
+                            let queued = ServerEvent::Queued { position };
+                            Self::send_message(&mut *write.lock(), &queued)?;
+                        }
+                        QueueNotice::Busy => {
+                            Self::send_message(&mut *write.lock(), &ServerEvent::Busy)?;
+                            notice = QueueNotice::Silent;
+                        }
+                        QueueNotice::Silent => {}
+                    },
+                    queue::Update::Granted => return Ok(true),
+                    queue::Update::Suspended => {}
+                },
+                recv(requests) -> req => match req {
+                    Ok(ClientRequest::Stop) | Err(_) => return Ok(false),
+                    Ok(req) => pending.push(req),
+                },
+            }
+        }
+    }
+
+    fn serve_connected<R: Read + Send + 'static>(
+        &self,
+        read: R,
+        write: &SharedWrite,
+        client_id: usize,
+        requires_token: bool,
+    ) -> eyre::Result<()> {
         let mut lines = BufReader::new(read).lines();
-        let registration_raw = lines
-            .next()
-            .ok_or_else(|| eyre!("didn't receive registration"))??;
+        let registration_raw = if let Some(line) = lines.next() {
+            line?
+        } else {
+            // Also how other instances check if this one is alive
+            tracing::debug!("client disconnected before registering");
+            return Ok(());
+        };
         let registration: Registration = serde_json::from_str(&registration_raw)?;
         if registration.protocol_version > PROTOCOL_VERSION {
-            Self::send_message(&mut write, &ServerEvent::ServerTooOld(PROTOCOL_VERSION))?;
+            Self::send_message(
+                &mut *write.lock(),
+                &ServerEvent::ServerTooOld(PROTOCOL_VERSION),
+            )?;
             bail!(
                 "server is too old for client {} with protocol version {}",
                 client_id,
@@ -89,35 +305,75 @@ impl Server {
             );
         }
 
-        let _guard = if let Some(guard) = self.busy.try_lock() {
-            guard
+        if requires_token && !self.authorized(&registration) {
+            Self::send_message(&mut *write.lock(), &ServerEvent::Unauthorized)?;
+            bail!("client {} presented invalid token", client_id);
+        }
+
+        let history = registration
+            .history_key
+            .as_deref()
+            .map(History::open)
+            .transpose()
+            .unwrap_or_else(|err| {
+                tracing::warn!("selection history disabled: {:#}", err);
+                None
+            });
+        let bonuses = history
+            .as_ref()
+            .map(History::bonuses)
+            .transpose()
+            .unwrap_or_else(|err| {
+                tracing::warn!("failed to load selection history: {:#}", err);
+                None
+            })
+            .unwrap_or_default();
+
+        let (requests_sender, requests) = channel::unbounded();
+        let _reader_thread =
+            thread::spawn(move || Self::read_requests(lines, &requests_sender, &bonuses));
+
+        let ticket = if let Some(ticket) =
+            self.queue
+                .join(client_id, registration.priority, !registration.no_wait)
+        {
+            ticket
         } else {
-            Self::send_message(&mut write, &ServerEvent::Busy)?;
-            self.busy.lock()
+            Self::send_message(&mut *write.lock(), &ServerEvent::Busy)?;
+            return Ok(());
         };
 
-        let (sender, receiver) = channel::unbounded();
-        let (control_sender, control_receiver) = channel::bounded(1);
-        ui_sender.send(ui::InitialState {
-            client_id,
-            events: sender,
-            control: control_sender,
-            matcher: registration.matcher,
-        })?;
+        let legacy = registration.protocol_version == 0;
+        let notice = if legacy {
+            QueueNotice::Busy
+        } else {
+            QueueNotice::Positions
+        };
+        let mut pending = Vec::new();
+        if !Self::wait_in_queue(&ticket, &requests, &mut pending, write, notice)? {
+            tracing::info!("client left the queue");
+            return Ok(());
+        }
 
-        let control = control_receiver
-            .recv()
-            .wrap_err("failed to receive ExtEventSink from UI thread")?;
-        drop(control_receiver);
+        let (sender, receiver) = channel::unbounded();
+        let mut control = self.show(client_id, &registration, &sender)?;
 
-        Self::send_message(&mut write, &ServerEvent::Registered(client_id))?;
+        Self::send_message(&mut *write.lock(), &ServerEvent::Registered(client_id))?;
 
-        let write = Arc::new(Mutex::new(write));
-        let events_write = Arc::clone(&write);
+        let events_write = Arc::clone(write);
+        let subscription = registration.subscribe_to;
+        let debounce = Duration::from_millis(registration.debounce_ms);
+        let protocol_version = registration.protocol_version;
         let _events_thread = thread::spawn(move || {
-            if let Err(err) =
-                Self::send_events(&receiver, registration.subscribe_to, &*events_write)
-            {
+            let result = Self::send_events(
+                &receiver,
+                subscription,
+                debounce,
+                &*events_write,
+                history.as_ref(),
+                protocol_version,
+            );
+            if let Err(err) = result {
                 tracing::info!(
                     client_id = client_id,
                     "client stopped listening for events: {}",
@@ -126,99 +382,288 @@ impl Server {
             }
         });
 
-        let mut stopped = false;
-        for line in lines {
-            let line = line?;
-            let req: Result<ClientRequest, _> = serde_json::from_str(&line);
-            match req {
-                Ok(req) => {
-                    let stop = matches!(req, ClientRequest::Stop);
-                    control.submit_command(
-                        CLIENT_REQUEST_SELECTOR,
-                        Box::new(req),
-                        Target::Global,
-                    )?;
-                    if stop {
-                        stopped = true;
-                        break;
+        loop {
+            for req in pending.drain(..) {
+                if Self::forward(&control, req)? {
+                    return Ok(());
+                }
+            }
+
+            channel::select! {
+                recv(requests) -> req => {
+                    // Disconnected client is treated as stopped
+                    if Self::forward(&control, req.unwrap_or(ClientRequest::Stop))? {
+                        return Ok(());
                     }
                 }
-                Err(err) => {
-                    tracing::warn!(
-                        line = line.as_str(),
-                        "failed to parse client request: {}",
-                        err
-                    );
+                recv(ticket.updates()) -> update => {
+                    if update? != queue::Update::Suspended {
+                        continue;
+                    }
+
+                    tracing::info!("suspended by a client with higher priority");
+                    // Window may already be closed by the user
+                    if let Err(err) = control.suspend() {
+                        tracing::warn!("failed to suspend window: {}", err);
+                    }
+                    let notice = if legacy {
+                        QueueNotice::Silent
+                    } else {
+                        Self::send_message(&mut *write.lock(), &ServerEvent::Suspended)?;
+                        QueueNotice::Positions
+                    };
+
+                    if !Self::wait_in_queue(&ticket, &requests, &mut pending, write, notice)? {
+                        tracing::info!("client left the queue while suspended");
+                        self.ui_sender.send(ui::Request::Discard(client_id))?;
+                        return Ok(());
+                    }
+
+                    control = self.show(client_id, &registration, &sender)?;
+                    if !legacy {
+                        Self::send_message(&mut *write.lock(), &ServerEvent::Resumed)?;
+                    }
                 }
             }
         }
+    }
 
-        if !stopped {
-            control.submit_command(
-                CLIENT_REQUEST_SELECTOR,
-                Box::new(ClientRequest::Stop),
-                Target::Global,
-            )?;
+    /// Ask the UI thread to show the window for a client
+    fn show(
+        &self,
+        client_id: usize,
+        registration: &Registration,
+        events: &Sender<Event>,
+    ) -> eyre::Result<ui::Handle> {
+        let accepted_at = self
+            .clients
+            .lock()
+            .get_mut(&client_id)
+            .and_then(|client| client.accepted_at.take());
+
+        let (control_sender, control_receiver) = channel::bounded(1);
+        self.ui_sender.send(ui::Request::Show(ui::InitialState {
+            client_id,
+            events: events.clone(),
+            control: control_sender,
+            matcher: registration.matcher,
+            ranking: registration.ranking,
+            filter: registration.filter,
+            case: registration.case,
+            normalize: registration.normalize,
+            max_lines: registration.max_lines,
+            accepted_at,
+        }))?;
+
+        let control = control_receiver
+            .recv()
+            .wrap_err("failed to receive window handle from UI thread")?;
+        if let Some(client) = self.clients.lock().get_mut(&client_id) {
+            client.control = Some(control.clone());
         }
+        Ok(control)
+    }
 
-        Ok(())
+    /// Pass a request to the UI, returning whether it was `Stop`
+    fn forward(control: &ui::Handle, req: ClientRequest) -> eyre::Result<bool> {
+        let stop = matches!(req, ClientRequest::Stop);
+        control.request(req)?;
+        Ok(stop)
+    }
+
+    fn authorized(&self, registration: &Registration) -> bool {
+        match (&self.config.token, &registration.token) {
+            (None, _) => true,
+            (Some(token), Some(candidate)) => token.matches(candidate),
+            (Some(_), None) => false,
+        }
+    }
+
+    fn peer_allowed(&self, credentials: PeerCredentials) -> bool {
+        credentials.uid == geteuid().as_raw() || self.config.allowed_uids.contains(&credentials.uid)
     }
 
     fn next_id(&self) -> usize {
         self.last_id.fetch_add(1, Ordering::Relaxed)
     }
 
-    fn start_ui() -> Sender<ui::InitialState> {
+    fn start_ui(multi_window: bool) -> Sender<ui::Request> {
         let (sender, receiver) = channel::bounded(1);
-        thread::spawn(move || ui::run(&receiver));
+        if multi_window {
+            thread::spawn(move || ui::run_multi(&receiver));
+        } else {
+            thread::spawn(move || ui::run(&receiver));
+        }
         sender
     }
 
-    fn run<S: NetStream + Send + 'static, L: Listener<Stream = S>>(
-        self: Arc<Self>,
-        listener: &L,
-    ) -> io::Result<Infallible> {
-        let ui_sender = Self::start_ui();
+    /// Accept clients from `listener` on the current thread until shutdown
+    pub fn accept_loop<L>(self: &Arc<Self>, listener: &L)
+    where
+        L: Listener,
+        L::Stream: NetStream + Send + 'static,
+    {
         loop {
-            let (stream, _addr) = match listener.accept() {
+            let (mut stream, _addr) = match listener.accept() {
                 Ok(pair) => pair,
                 Err(err) => {
-                    tracing::error!("failed to accept TCP connection: {}", err);
+                    tracing::error!("failed to accept connection: {}", err);
                     continue;
                 }
             };
+            let accepted_at = Instant::now();
+            if self.shutting_down.load(Ordering::SeqCst) {
+                break;
+            }
 
-            let this = Arc::clone(&self);
-            let ui_sender = ui_sender.clone();
+            let this = Arc::clone(self);
             thread::spawn(move || {
                 let client_id = this.next_id();
-                let _span = tracing::info_span!("client-thread", client_id = client_id);
+                let span = tracing::info_span!(
+                    "client-thread",
+                    client_id = client_id,
+                    peer_pid = tracing::field::Empty,
+                );
+                let _entered = span.enter();
 
-                let cloned_stream = match stream.try_clone() {
-                    Ok(cloned) => cloned,
+                match stream.peer_credentials() {
+                    Ok(Some(credentials)) => {
+                        span.record("peer_pid", &credentials.pid);
+                        if !this.peer_allowed(credentials) {
+                            tracing::warn!(
+                                uid = credentials.uid,
+                                "rejecting connection from another user"
+                            );
+                            Self::send_message(&mut stream, &ServerEvent::Unauthorized).ok();
+                            return;
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(err) => {
+                        tracing::error!("failed to get peer credentials: {}", err);
+                        return;
+                    }
+                }
+
+                let clones = stream
+                    .try_clone()
+                    .and_then(|read| Ok((read, stream.try_clone()?)));
+                let (read, write) = match clones {
+                    Ok(pair) => pair,
                     Err(err) => {
                         tracing::error!("failed to clone stream: {}", err);
                         return;
                     }
                 };
-                if let Err(err) = this.serve_client(stream, cloned_stream, client_id, &ui_sender) {
+                if let Err(err) =
+                    this.serve_client(read, write, client_id, L::REQUIRES_TOKEN, accepted_at)
+                {
                     tracing::error!("error while serving client: {}", err);
                 }
+
+                // Request reader thread may still hold its half of the stream
+                if let Err(err) = stream.shutdown() {
+                    tracing::debug!("failed to shut down stream: {}", err);
+                }
             });
         }
     }
 
-    pub fn run_tcp<A>(addr: A) -> io::Result<Infallible>
+    /// Accept clients from `listener` on a background thread
+    pub fn spawn_listener<L>(self: &Arc<Self>, listener: L) -> JoinHandle<()>
     where
-        A: ToSocketAddrs,
+        L: Listener + Send + 'static,
+        L::Stream: NetStream + Send + 'static,
     {
-        Self::new().run(&TcpListener::bind(addr)?)
+        let this = Arc::clone(self);
+        thread::spawn(move || this.accept_loop(&listener))
     }
 
-    pub fn run_unix<A>(addr: A) -> io::Result<Infallible>
-    where
-        A: AsRef<Path>,
-    {
-        Self::new().run(&UnixListener::bind(addr)?)
+    fn bind(self: &Arc<Self>, address: &Address) -> io::Result<()> {
+        tracing::info!("listening on {}", address);
+        match address {
+            Address::Tcp(addr) => {
+                self.spawn_listener(TcpListener::bind(addr)?);
+            }
+            Address::Unix(path) => {
+                let (listener, file) = unix_socket::bind(path)?;
+                self.socket_files.lock().push(file);
+                self.spawn_listener(listener);
+            }
+        }
+        Ok(())
+    }
+
+    fn adopt(self: &Arc<Self>, listener: ActivatedListener) {
+        match listener {
+            ActivatedListener::Tcp(listener) => {
+                tracing::info!(
+                    "listening on socket-activated tcp:{:?}",
+                    listener.local_addr()
+                );
+                self.spawn_listener(listener);
+            }
+            ActivatedListener::Unix(listener) => {
+                tracing::info!(
+                    "listening on socket-activated unix:{:?}",
+                    listener.local_addr()
+                );
+                self.spawn_listener(listener);
+            }
+        }
+    }
+
+    /// Stop accepting clients, notify connected ones and close the window
+    ///
+    /// Socket files created by this server are removed.
+    pub fn shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+
+        for (client_id, client) in self.clients.lock().drain() {
+            if let Err(err) =
+                Self::send_message(&mut *client.write.lock(), &ServerEvent::ShuttingDown)
+            {
+                tracing::warn!(
+                    client_id = client_id,
+                    "failed to notify client about shutdown: {}",
+                    err
+                );
+            }
+
+            if let Some(control) = client.control {
+                if let Err(err) = control.request(ClientRequest::Stop) {
+                    tracing::warn!(client_id = client_id, "failed to close window: {}", err);
+                }
+            }
+        }
+
+        self.socket_files.lock().clear();
+    }
+
+    /// Serve clients until SIGTERM or SIGINT is received, then shut down
+    fn run(self: &Arc<Self>, signals: &mut Signals) {
+        if let Some(signal) = signals.forever().next() {
+            tracing::info!(signal = signal, "received signal, shutting down");
+        }
+        self.shutdown();
+    }
+
+    /// Listen on all given addresses and socket-activated listeners at once,
+    /// serving every client with the same UI
+    pub fn run_many(
+        addresses: &[Address],
+        activated: Vec<ActivatedListener>,
+        config: Config,
+    ) -> io::Result<()> {
+        let mut signals = Signals::new([SIGTERM, SIGINT])?;
+        let this = Self::new(config);
+        for address in addresses {
+            this.bind(address)?;
+        }
+        for listener in activated {
+            this.adopt(listener);
+        }
+        this.run(&mut signals);
+        Ok(())
     }
 }
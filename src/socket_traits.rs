@@ -1,16 +1,22 @@
 use std::{
     io::{self, Read, Write},
-    net::{SocketAddr, TcpListener, TcpStream},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
     os::unix::{
         self,
+        io::AsRawFd as _,
         net::{UnixListener, UnixStream},
     },
 };
 
+use nix::sys::socket::{getsockopt, sockopt};
+
 pub trait Listener {
     type Stream;
     type SocketAddr;
 
+    /// Whether clients must present the server token, if there is one
+    const REQUIRES_TOKEN: bool;
+
     fn accept(&self) -> io::Result<(Self::Stream, Self::SocketAddr)>;
 }
 
@@ -18,6 +24,8 @@ impl Listener for TcpListener {
     type Stream = TcpStream;
     type SocketAddr = SocketAddr;
 
+    const REQUIRES_TOKEN: bool = true;
+
     fn accept(&self) -> io::Result<(Self::Stream, Self::SocketAddr)> {
         self.accept()
     }
@@ -27,23 +35,56 @@ impl Listener for UnixListener {
     type Stream = UnixStream;
     type SocketAddr = unix::net::SocketAddr;
 
+    const REQUIRES_TOKEN: bool = false;
+
     fn accept(&self) -> io::Result<(Self::Stream, Self::SocketAddr)> {
         self.accept()
     }
 }
 
+/// Identity of the process on the other end of a local socket
+#[derive(Debug, Clone, Copy)]
+pub struct PeerCredentials {
+    pub pid: i32,
+    pub uid: u32,
+}
+
 pub trait NetStream: Read + Write + Sized {
     fn try_clone(&self) -> io::Result<Self>;
+
+    /// Close both halves of the connection, including all clones
+    fn shutdown(&self) -> io::Result<()>;
+
+    /// Credentials of the connected peer, if the transport provides them
+    fn peer_credentials(&self) -> io::Result<Option<PeerCredentials>> {
+        Ok(None)
+    }
 }
 
 impl NetStream for TcpStream {
     fn try_clone(&self) -> io::Result<Self> {
         self.try_clone()
     }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Both)
+    }
 }
 
 impl NetStream for UnixStream {
     fn try_clone(&self) -> io::Result<Self> {
         self.try_clone()
     }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Both)
+    }
+
+    fn peer_credentials(&self) -> io::Result<Option<PeerCredentials>> {
+        let credentials = getsockopt(self.as_raw_fd(), sockopt::PeerCredentials)?;
+        Ok(Some(PeerCredentials {
+            pid: credentials.pid(),
+            uid: credentials.uid(),
+        }))
+    }
 }
@@ -0,0 +1,29 @@
+use std::{io, net::TcpListener, os::unix::net::UnixListener};
+
+use listenfd::ListenFd;
+
+/// Listening socket passed by the service manager
+#[derive(Debug)]
+pub enum ActivatedListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Take all sockets passed through `LISTEN_FDS`/`LISTEN_PID`
+///
+/// Returns an empty list if the server wasn't socket-activated.
+pub fn activated_listeners() -> io::Result<Vec<ActivatedListener>> {
+    let mut fds = ListenFd::from_env();
+    let mut listeners = Vec::with_capacity(fds.len());
+    for idx in 0..fds.len() {
+        let listener = if let Ok(Some(listener)) = fds.take_tcp_listener(idx) {
+            ActivatedListener::Tcp(listener)
+        } else if let Some(listener) = fds.take_unix_listener(idx)? {
+            ActivatedListener::Unix(listener)
+        } else {
+            continue;
+        };
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
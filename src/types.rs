@@ -1,10 +1,10 @@
-use std::{borrow::Cow, ops::Deref, sync::Arc};
+use std::{borrow::Cow, collections::HashSet, ops::Deref, sync::Arc};
 
-use druid::{im, widget::ListIter, Data, Selector};
+use druid::{im, Data};
 use enumflags2::{bitflags, BitFlags};
 use serde::{Deserialize, Serialize};
 
-use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher as _};
+use crate::matching::{Hit, Scorer};
 
 #[bitflags(default = Select | WindowClosed)]
 #[repr(u8)]
@@ -19,9 +19,9 @@ pub enum Subscription {
 
 #[derive(Debug)]
 pub enum Event {
-    Select(Option<usize>),
+    Select(Option<Box<Choice>>),
     CursorMove(usize),
-    InputChange(String),
+    InputChange { input: String, seq: u64 },
     WindowClosed,
 }
 
@@ -31,7 +31,7 @@ impl Event {
         match self {
             Event::Select(_) => subscription.contains(Subscription::Select),
             Event::CursorMove(_) => subscription.contains(Subscription::CursorMove),
-            Event::InputChange(_) => subscription.contains(Subscription::InputChange),
+            Event::InputChange { .. } => subscription.contains(Subscription::InputChange),
             Event::WindowClosed => subscription.contains(Subscription::WindowClosed),
         }
     }
@@ -42,6 +42,19 @@ impl Event {
 pub enum Matcher {
     None,
     Fuzzy,
+    /// Whole text equal to the input
+    Exact,
+    /// Text starting with the input
+    Prefix,
+    /// Text containing the input, ignoring case unless `case` is set
+    Substring,
+    /// Text matching the input as a regular expression
+    Regex,
+    /// Input matching initials of words, possibly followed by more of their letters
+    Initials,
+    /// fzf's extended syntax: `'exact`, `^prefix`, `suffix$`, `!negation` and `|` for OR
+    /// between space-separated fuzzy terms
+    Extended,
 }
 
 impl Default for Matcher {
@@ -50,33 +63,176 @@ impl Default for Matcher {
     }
 }
 
+impl Matcher {
+    /// Whether case matters when no `case` is set, as it was before it could be chosen
+    #[must_use]
+    pub fn default_case(self) -> Case {
+        match self {
+            // fuzzy-matcher and fzf's extended syntax are smart-case on their own
+            Matcher::None | Matcher::Fuzzy | Matcher::Extended => Case::Smart,
+            Matcher::Exact | Matcher::Prefix | Matcher::Regex => Case::Respect,
+            Matcher::Substring | Matcher::Initials => Case::Ignore,
+        }
+    }
+
+    /// Whether choices not matching an input can't match it with more characters added
+    #[must_use]
+    pub fn is_monotonic(self) -> bool {
+        match self {
+            Matcher::Fuzzy | Matcher::Prefix | Matcher::Substring | Matcher::Initials => true,
+            Matcher::None | Matcher::Exact | Matcher::Regex | Matcher::Extended => false,
+        }
+    }
+}
+
+/// Whether letter case matters when matching
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Data, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Case {
+    /// Case matters only if the input has uppercase letters
+    Smart,
+    Ignore,
+    Respect,
+}
+
+impl Case {
+    /// Whether case matters when matching the input
+    #[must_use]
+    pub fn is_sensitive(self, input: &str) -> bool {
+        match self {
+            Case::Smart => input.chars().any(char::is_uppercase),
+            Case::Ignore => false,
+            Case::Respect => true,
+        }
+    }
+}
+
+/// How the match score and the client's priority combine into the order of choices
+#[derive(Debug, Clone, Copy, PartialEq, Data, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Ranking {
+    /// Order by score only
+    Score,
+    /// Order by score, then by priority
+    Tiebreak,
+    /// Order by `weight * priority - score`, then by priority
+    Weighted { weight: f64 },
+}
+
+impl Default for Ranking {
+    fn default() -> Self {
+        Self::Tiebreak
+    }
+}
+
+impl Ranking {
+    /// Sort key of a matched choice, lower goes first
+    ///
+    /// The frecency `bonus` adds to the score. Choices that didn't match go last,
+    /// in the client's order.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+    pub fn rank(self, priority: i64, bonus: i64, score: Option<i64>) -> (i64, i64) {
+        let score = match score {
+            Some(score) => score.saturating_add(bonus),
+            None => return (i64::MAX, priority),
+        };
+
+        match self {
+            Ranking::Score => (-score, 0),
+            Ranking::Tiebreak => (-score, priority),
+            Ranking::Weighted { weight } => (
+                ((weight * priority as f64) as i64).saturating_sub(score),
+                priority,
+            ),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Registration {
+    /// Version 1 added the queue, suspension and sequence numbers of input changes;
+    /// version 0 clients keep getting messages they understand
     pub protocol_version: u8,
     #[serde(default)]
     pub subscribe_to: BitFlags<Subscription>,
     #[serde(default)]
     pub matcher: Matcher,
+    #[serde(default)]
+    pub ranking: Ranking,
+    /// Hide choices not matching the input instead of putting them last
+    #[serde(default)]
+    pub filter: bool,
+    /// Whether case matters, `Matcher::default_case` if not set
+    #[serde(default)]
+    pub case: Option<Case>,
+    /// Match ignoring diacritics and compatibility forms, so "cafe" finds "café"
+    #[serde(default)]
+    pub normalize: bool,
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Fail with `Busy` instead of waiting in the queue
+    #[serde(default)]
+    pub no_wait: bool,
+    /// Remember selections under this key and rank frequently and recently selected
+    /// choices higher
+    #[serde(default)]
+    pub history_key: Option<String>,
+    /// Clients with higher priority suspend the active one
+    #[serde(default)]
+    pub priority: i64,
+    /// Coalesce `InputChange` events arriving within this many milliseconds; 0 disables
+    #[serde(default)]
+    pub debounce_ms: u64,
+    /// Show at most this many choices at once, scrolling to the selected one
+    #[serde(default)]
+    pub max_lines: Option<usize>,
 }
 
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "snake_case", tag = "key", content = "data")]
 pub enum ServerEvent {
     Busy,
+    Queued {
+        position: usize,
+    },
+    Unauthorized,
     Registered(usize),
     ServerTooOld(u8),
+    Suspended,
+    Resumed,
     Select(Option<usize>),
     CursorMove(usize),
-    InputChange(String),
+    InputChange {
+        input: String,
+        seq: u64,
+    },
+    /// `InputChange` as protocol 0 clients expect it, without `seq`
+    #[serde(rename = "input_change")]
+    InputChangeV0(String),
     WindowClosed,
+    ShuttingDown,
+}
+
+impl ServerEvent {
+    /// Event in the shape clients of the protocol version expect
+    #[must_use]
+    pub fn for_protocol(self, version: u8) -> Self {
+        match self {
+            ServerEvent::InputChange { input, .. } if version == 0 => {
+                ServerEvent::InputChangeV0(input)
+            }
+            event => event,
+        }
+    }
 }
 
 impl From<Event> for ServerEvent {
     fn from(ui_event: Event) -> Self {
         match ui_event {
-            Event::Select(n) => ServerEvent::Select(n),
+            Event::Select(choice) => ServerEvent::Select(choice.map(|choice| choice.id)),
             Event::CursorMove(n) => ServerEvent::CursorMove(n),
-            Event::InputChange(input) => ServerEvent::InputChange(input),
+            Event::InputChange { input, seq } => ServerEvent::InputChange { input, seq },
             Event::WindowClosed => ServerEvent::WindowClosed,
         }
     }
@@ -104,16 +260,143 @@ impl Deref for ArcStr {
 }
 
 #[derive(Debug, Clone, Data, PartialOrd, Ord, PartialEq, Eq, Deserialize)]
+#[serde(from = "ChoiceRepr")]
 pub struct Choice {
-    #[serde(default)]
+    /// Position among other choices, lower goes first
+    pub rank: (i64, i64),
+    /// Match score against the input, `None` if it didn't match or there's no input
+    pub score: Option<i64>,
+    /// Indices of characters of `text` matching the input, for highlighting
+    pub positions: Arc<[usize]>,
+    /// Order requested by the client, lower goes first
     pub priority: i64,
+    /// Frecency of the choice in the selection history, higher goes first
+    pub bonus: i64,
     pub id: usize,
     pub text: ArcStr,
+    /// Matched like `text`, but never shown
+    pub keywords: Arc<[ArcStr]>,
+}
+
+impl Choice {
+    /// Score against the query, going back to the client's order if there's nothing to match
+    pub fn match_query(&mut self, scorer: &Scorer, query: Query<'_>) {
+        let hit = if query.is_active() {
+            self.best_hit(scorer)
+        } else {
+            None
+        };
+        self.set_hit(hit, query);
+    }
+
+    /// Best hit among the text and keywords, preferring the text on ties
+    ///
+    /// Keywords aren't shown, so their hits don't highlight anything.
+    fn best_hit(&self, scorer: &Scorer) -> Option<Hit> {
+        self.keywords
+            .iter()
+            .filter_map(|keyword| scorer.score(keyword))
+            .fold(scorer.score(&self.text), |best, hit| match best {
+                Some(best) if best.score >= hit.score => Some(best),
+                _ => Some(Hit {
+                    score: hit.score,
+                    positions: Vec::new(),
+                }),
+            })
+    }
+
+    pub fn set_hit(&mut self, hit: Option<Hit>, query: Query<'_>) {
+        let (score, positions) = match hit {
+            Some(Hit { score, positions }) => (Some(score), Arc::from(positions)),
+            None => (None, Arc::from(Vec::new())),
+        };
+        self.score = score;
+        self.positions = positions;
+        self.rank = if query.is_active() {
+            query.ranking.rank(self.priority, self.bonus, score)
+        } else {
+            unmatched_rank(self.priority, self.bonus)
+        };
+    }
+
+    /// Set the frecency bonus of a choice that wasn't matched yet
+    pub fn set_bonus(&mut self, bonus: i64) {
+        self.bonus = bonus;
+        self.rank = unmatched_rank(self.priority, bonus);
+    }
+}
+
+/// Sort key with nothing to match: frequently selected choices first, then the client's order
+fn unmatched_rank(priority: i64, bonus: i64) -> (i64, i64) {
+    (-bonus, priority)
+}
+
+#[derive(Deserialize)]
+struct ChoiceRepr {
+    #[serde(default)]
+    priority: i64,
+    id: usize,
+    text: ArcStr,
+    #[serde(default)]
+    keywords: Vec<ArcStr>,
+}
+
+impl From<ChoiceRepr> for Choice {
+    fn from(
+        ChoiceRepr {
+            priority,
+            id,
+            text,
+            keywords,
+        }: ChoiceRepr,
+    ) -> Self {
+        Choice {
+            rank: unmatched_rank(priority, 0),
+            score: None,
+            positions: Arc::from(Vec::new()),
+            priority,
+            bonus: 0,
+            id,
+            text,
+            keywords: Arc::from(keywords),
+        }
+    }
+}
+
+/// Input to score choices against and how to order them by score
+#[derive(Debug, Clone, Copy)]
+pub struct Query<'a> {
+    pub input: &'a str,
+    pub matcher: Matcher,
+    pub ranking: Ranking,
+    /// Hide choices not matching the input
+    pub filter: bool,
+    /// `Matcher::default_case` if not set
+    pub case: Option<Case>,
+    /// Compare texts in NFKD without combining marks
+    pub normalize: bool,
+}
+
+impl Query<'_> {
+    /// Whether choices are matched at all, otherwise they keep the client's order
+    #[must_use]
+    pub fn is_active(&self) -> bool {
+        self.matcher != Matcher::None && !self.input.is_empty()
+    }
+
+    /// Whether a choice scored against this query should be hidden
+    #[must_use]
+    pub fn hides(&self, choice: &Choice) -> bool {
+        self.filter && self.is_active() && choice.score.is_none()
+    }
 }
 
 #[derive(Debug, Default, Clone, Data, Deserialize)]
 pub struct ChoiceSet {
     pub options: im::OrdSet<Choice>,
+    /// Choices filtered out for not matching the input
+    #[serde(skip)]
+    pub hidden: im::OrdSet<Choice>,
     #[serde(default)]
     pub selected: Option<usize>,
 }
@@ -131,20 +414,101 @@ impl ChoiceSet {
         self.options.is_empty()
     }
 
-    pub fn fuzzy_sort(&mut self, input: &str) {
-        take_mut::take(&mut self.options, |options| {
-            let matcher = SkimMatcherV2::default();
-            options
-                .into_iter()
-                .map(|mut option| {
-                    option.priority = -matcher
-                        .fuzzy_match(&*option.text, input)
-                        .unwrap_or(i64::MIN + 1);
-                    option
-                })
-                .collect()
+    /// Number of choices including hidden ones
+    #[inline]
+    #[must_use]
+    pub fn total_len(&self) -> usize {
+        self.options.len() + self.hidden.len()
+    }
+
+    /// Id of the selected choice
+    #[must_use]
+    pub fn selected_id(&self) -> Option<usize> {
+        self.selected
+            .and_then(|selected| self.options.iter().nth(selected))
+            .map(|option| option.id)
+    }
+
+    /// Add choices, scoring them against `query` if it's given
+    pub fn append(&mut self, choices: impl IntoIterator<Item = Choice>, query: Option<Query<'_>>) {
+        let scorer = query.map(Scorer::new_or_invalid);
+        self.edit(|this| {
+            for mut choice in choices {
+                if let (Some(scorer), Some(query)) = (&scorer, query) {
+                    choice.match_query(scorer, query);
+                }
+                this.insert(choice, query);
+            }
         });
     }
+
+    /// Remove choices with the given ids
+    pub fn remove(&mut self, ids: &[usize]) {
+        let ids: HashSet<_> = ids.iter().copied().collect();
+        self.edit(|this| this.remove_where(|option| ids.contains(&option.id)));
+    }
+
+    /// Replace the choice with the same id, or add it if there is none
+    pub fn upsert(&mut self, mut choice: Choice, query: Option<Query<'_>>) {
+        if let Some(query) = query {
+            choice.match_query(&Scorer::new_or_invalid(query), query);
+        }
+        self.edit(|this| {
+            this.remove_where(|option| option.id == choice.id);
+            this.insert(choice, query);
+        });
+    }
+
+    /// Replace all choices with rescored ones
+    pub fn replace(&mut self, options: im::OrdSet<Choice>, hidden: im::OrdSet<Choice>) {
+        self.edit(|this| {
+            this.options = options;
+            this.hidden = hidden;
+        });
+    }
+
+    /// Put a choice among the shown or the hidden ones, depending on its score
+    fn insert(&mut self, choice: Choice, query: Option<Query<'_>>) {
+        if query.map_or(false, |query| query.hides(&choice)) {
+            self.hidden.insert(choice);
+        } else {
+            self.options.insert(choice);
+        }
+    }
+
+    fn remove_where(&mut self, predicate: impl Fn(&Choice) -> bool) {
+        remove_where(&mut self.options, &predicate);
+        remove_where(&mut self.hidden, &predicate);
+    }
+
+    /// Edit choices in place, keeping the same choice selected while it's shown
+    fn edit(&mut self, f: impl FnOnce(&mut Self)) {
+        let selected_id = self.selected_id();
+        f(self);
+
+        if let Some(id) = selected_id {
+            let fallback = self
+                .selected
+                .map(|selected| selected.min(self.len().saturating_sub(1)));
+            self.selected = self
+                .options
+                .iter()
+                .position(|option| option.id == id)
+                .or(fallback)
+                .filter(|_| !self.is_empty());
+        }
+    }
+}
+
+fn remove_where(options: &mut im::OrdSet<Choice>, predicate: impl Fn(&Choice) -> bool) {
+    let removed: Vec<_> = options
+        .iter()
+        .filter(|option| predicate(option))
+        .cloned()
+        .collect();
+    for option in removed {
+        options.remove(&option);
+    }
 }
 
 #[derive(Debug, Clone, Data)]
@@ -161,51 +525,32 @@ impl Indices {
     }
 }
 
-impl ListIter<(Indices, Choice)> for ChoiceSet {
-    fn for_each(&self, mut cb: impl FnMut(&(Indices, Choice), usize)) {
-        let selected = self.selected;
-        for (idx, item) in self.options.iter().enumerate() {
-            cb(
-                &(
-                    Indices {
-                        current: idx,
-                        selected,
-                    },
-                    item.clone(),
-                ),
-                idx,
-            );
-        }
-    }
-
-    fn for_each_mut(&mut self, mut cb: impl FnMut(&mut (Indices, Choice), usize)) {
-        let selected = self.selected;
-        for (idx, item) in self.options.iter().enumerate() {
-            cb(
-                &mut (
-                    Indices {
-                        current: idx,
-                        selected,
-                    },
-                    item.clone(),
-                ),
-                idx,
-            );
-        }
-    }
-
-    #[inline]
-    fn data_len(&self) -> usize {
-        self.len()
-    }
-}
-
 #[derive(Debug, Deserialize)]
 #[serde(tag = "key", content = "data", rename_all = "snake_case")]
 pub enum ClientRequest {
     Stop,
-    SetChoices(ChoiceSet),
+    SetChoices(SetChoices),
+    AppendChoices(Vec<Choice>),
+    RemoveChoices {
+        ids: Vec<usize>,
+    },
+    /// Replace the choice with the same id
+    UpdateChoice(Choice),
+    /// Client finished sending choices after `SetChoices` with `loading` set
+    ChoicesComplete,
+    /// Switch to another matcher, rescoring the choices
+    SetMatcher(Matcher),
     SetInput(String),
 }
 
-pub const CLIENT_REQUEST_SELECTOR: Selector<ClientRequest> = Selector::new("ClientRequest");
+#[derive(Debug, Deserialize)]
+pub struct SetChoices {
+    /// `seq` of the `InputChange` these choices answer; stale answers are dropped
+    #[serde(default)]
+    pub seq: Option<u64>,
+    /// More choices will follow in `AppendChoices` until `ChoicesComplete`
+    #[serde(default)]
+    pub loading: bool,
+    #[serde(flatten)]
+    pub choices: ChoiceSet,
+}
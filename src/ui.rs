@@ -1,43 +1,330 @@
-use std::fmt::Debug;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    mem,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
 
 use crossbeam::channel::{Receiver, Sender};
 use druid::{
+    im,
     keyboard_types::Key,
-    theme,
-    widget::{prelude::*, Controller, Flex, Label, List, Painter, TextBox},
-    AppLauncher, Command, Data, ExtEventSink, KeyEvent, Lens, Rect, Screen, Selector, Target,
-    WidgetExt as _, WindowDesc, WindowHandle,
+    lens, theme,
+    widget::{prelude::*, Controller, Either, Flex, Label, Painter, RawLabel, SizedBox, TextBox},
+    AppDelegate, AppLauncher, Color, Command, Data, DelegateCtx, ExtEventError, ExtEventSink,
+    Handled, KeyEvent, Lens, Rect, Screen, Selector, SingleUse, Target, TimerToken, WidgetExt as _,
+    WindowDesc, WindowId,
 };
 
-use crate::types::{
-    self, Choice, ChoiceSet, ClientRequest, Indices, Matcher, CLIENT_REQUEST_SELECTOR,
+use crate::{
+    matching::{self, Job, Matched, Ticket},
+    types::{
+        self, Case, Choice, ChoiceSet, ClientRequest, Indices, Matcher, Query, Ranking, SetChoices,
+    },
 };
 
+use self::{choice_list::ChoiceList, highlighted_label::HighlightedLabel};
+
+mod choice_list;
+mod highlighted_label;
+
+/// Rows shown at once unless the client asks for another number
+pub const DEFAULT_MAX_LINES: usize = 10;
+
 struct WindowMoved;
 
 static WINDOW_MOVED_SELECTOR: Selector<WindowMoved> = Selector::new("WindowMoved");
 
+/// Request from a client along with its id
+const CLIENT_REQUEST_SELECTOR: Selector<(usize, ClientRequest)> = Selector::new("ClientRequest");
+
+/// Start a session for a client
+const OPEN_SELECTOR: Selector<SingleUse<InitialState>> = Selector::new("Open");
+
+/// Put the session of a client aside, keeping its state to be restored later
+const SUSPEND_SELECTOR: Selector<usize> = Selector::new("Suspend");
+
+/// Forget the state of a suspended client
+const DISCARD_SELECTOR: Selector<usize> = Selector::new("Discard");
+
+/// Show or hide the persistent window
+const SET_VISIBLE_SELECTOR: Selector<bool> = Selector::new("SetVisible");
+
 #[derive(Debug, Default, Clone, Data, Lens)]
 pub struct State {
+    /// Client owning the window, if any
+    client_id: Option<usize>,
+    #[data(ignore)]
+    events: Option<Sender<types::Event>>,
+    #[data(ignore)]
+    accepted_at: Option<Instant>,
+
     matcher: Matcher,
+    ranking: Ranking,
+    /// Hide choices not matching the input
+    filter: bool,
+    case: Option<Case>,
+    normalize: bool,
+    /// Choices shown at once, the rest are scrolled to
+    max_lines: usize,
     window_moved: bool,
 
     input: String,
+    /// Bumped on every input change
+    input_seq: u64,
+    /// `input_seq` answered by the last applied `SetChoices`
+    applied_seq: u64,
     elems: ChoiceSet,
+    /// Client is still streaming choices
+    loading: bool,
+
+    #[data(ignore)]
+    engine: Option<matching::Engine>,
+    /// Matching job whose result is awaited
+    #[data(ignore)]
+    matching: Option<PendingMatch>,
+    /// Ids of choices not matching the input of the last match, along with that input
+    #[data(ignore)]
+    rejected: Option<(String, Arc<HashSet<usize>>)>,
+    /// Why the input couldn't be matched, like an invalid regex
+    match_error: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+struct PendingMatch {
+    ticket: Ticket,
+    /// Select the best match once it's known
+    select_first: bool,
+    /// Edits made since the job started, to be replayed on its result
+    edits: Vec<Edit>,
+}
+
+/// In-place change of the choices requested by the client
+#[derive(Debug, Clone)]
+enum Edit {
+    Append(Vec<Choice>),
+    Remove(Vec<usize>),
+    Update(Choice),
+}
+
+impl Edit {
+    fn apply(self, elems: &mut ChoiceSet, query: Option<Query<'_>>) {
+        match self {
+            Edit::Append(choices) => elems.append(choices, query),
+            Edit::Remove(ids) => elems.remove(&ids),
+            Edit::Update(choice) => elems.upsert(choice, query),
+        }
+    }
+}
+
+impl State {
+    fn new(init: &InitialState, engine: matching::Engine) -> Self {
+        State {
+            client_id: Some(init.client_id),
+            events: Some(init.events.clone()),
+            accepted_at: init.accepted_at,
+            matcher: init.matcher,
+            ranking: init.ranking,
+            filter: init.filter,
+            case: init.case,
+            normalize: init.normalize,
+            max_lines: init.max_lines.unwrap_or(DEFAULT_MAX_LINES),
+            engine: Some(engine),
+            ..State::default()
+        }
+    }
+
+    /// Edit choices, scoring new ones against the input
+    fn edit(&mut self, edit: Edit) {
+        match &edit {
+            // Updated text may match now
+            Edit::Update(_) => self.rejected = None,
+            // Ids of removed choices may come back with new text
+            Edit::Append(choices) => {
+                if let Some((_, rejected)) = &mut self.rejected {
+                    let rejected = Arc::make_mut(rejected);
+                    for choice in choices {
+                        rejected.remove(&choice.id);
+                    }
+                }
+            }
+            Edit::Remove(_) => {}
+        }
+        if let Some(pending) = &mut self.matching {
+            pending.edits.push(edit.clone());
+        }
+
+        let query = Query {
+            input: &self.input,
+            matcher: self.matcher,
+            ranking: self.ranking,
+            filter: self.filter,
+            case: self.case,
+            normalize: self.normalize,
+        };
+        edit.apply(&mut self.elems, Some(query));
+    }
+
+    /// Rescore choices against the input on the matching thread
+    fn start_matching(&mut self, target: Target, select_first: bool) {
+        let (engine, client_id) = match (&self.engine, self.client_id) {
+            (Some(engine), Some(client_id)) => (engine, client_id),
+            _ => return,
+        };
+
+        self.cancel_matching();
+        // Choices not matching a prefix of the input can't match the whole of it
+        let rejected = self
+            .rejected
+            .as_ref()
+            .filter(|(input, _)| {
+                self.matcher.is_monotonic() && self.input.starts_with(input.as_str())
+            })
+            .map(|(_, rejected)| Arc::clone(rejected));
+        let ticket = engine.submit(Job {
+            client_id,
+            target,
+            input: self.input.clone(),
+            matcher: self.matcher,
+            ranking: self.ranking,
+            filter: self.filter,
+            case: self.case,
+            normalize: self.normalize,
+            options: self.elems.options.clone(),
+            hidden: self.elems.hidden.clone(),
+            rejected,
+        });
+        self.matching = Some(PendingMatch {
+            ticket,
+            select_first,
+            edits: Vec::new(),
+        });
+    }
+
+    fn cancel_matching(&mut self) {
+        if let Some(pending) = self.matching.take() {
+            pending.ticket.cancel();
+        }
+    }
+
+    /// Apply a request of the client owning this state, results of matching go to `target`
+    ///
+    /// Returns `true` if the client stopped the session.
+    fn apply_request(&mut self, request: &ClientRequest, target: Target) -> bool {
+        match request {
+            ClientRequest::Stop => return true,
+            ClientRequest::SetChoices(SetChoices { seq: Some(seq), .. })
+                if *seq < self.applied_seq =>
+            {
+                tracing::debug!(seq = seq, "dropping stale choices");
+            }
+            ClientRequest::SetChoices(SetChoices {
+                seq,
+                loading,
+                choices,
+            }) => {
+                if let Some(seq) = seq {
+                    self.applied_seq = *seq;
+                }
+                self.loading = *loading;
+
+                self.elems = choices.clone();
+                if let Some(selected) = self.elems.selected {
+                    if selected >= choices.len() {
+                        self.elems.selected = Some(choices.len() - 1);
+                    }
+                }
+
+                self.cancel_matching();
+                self.rejected = None;
+                if self.matcher != Matcher::None {
+                    self.start_matching(target, false);
+                }
+            }
+            ClientRequest::AppendChoices(choices) => {
+                self.edit(Edit::Append(choices.clone()));
+            }
+            ClientRequest::RemoveChoices { ids } => {
+                self.edit(Edit::Remove(ids.clone()));
+            }
+            ClientRequest::UpdateChoice(choice) => {
+                self.edit(Edit::Update(choice.clone()));
+            }
+            ClientRequest::ChoicesComplete => self.loading = false,
+            ClientRequest::SetMatcher(matcher) => {
+                self.matcher = *matcher;
+                self.rejected = None;
+                self.match_error = None;
+                // Even with no matcher, the client's order has to be restored
+                self.start_matching(target, false);
+            }
+            ClientRequest::SetInput(input) => {
+                self.input = input.clone();
+            }
+        }
+        false
+    }
+
+    /// Show the result of the pending match, dropping outdated ones
+    fn finish_matching(&mut self, matched: &Matched) {
+        let pending = match self.matching.take() {
+            Some(pending) if pending.ticket.generation == matched.generation => pending,
+            other => {
+                self.matching = other;
+                return;
+            }
+        };
+
+        // Filtering may hide the selected choice or shorten the list past the selection
+        self.elems
+            .replace(matched.options.clone(), matched.hidden.clone());
+        self.rejected = Some((matched.input.clone(), Arc::clone(&matched.rejected)));
+        self.match_error = matched.error.clone();
+        if pending.select_first {
+            self.elems.selected = Some(0).filter(|_| !self.elems.is_empty());
+        }
+        for edit in pending.edits {
+            self.edit(edit);
+        }
+    }
 }
 
 pub struct TypeWatcher {
-    events: Sender<types::Event>,
+    /// Window is reused by the next client instead of being closed
+    persistent: bool,
+    hide_timer: Option<TimerToken>,
 }
 
 impl TypeWatcher {
-    /// Send event to the controlling thread, closing window on error
-    fn send_event(&self, window: &WindowHandle, event: types::Event) {
-        if self.events.send(event).is_err() {
+    /// Send event to the controlling thread, ending the session on error
+    fn send_event(&self, ctx: &mut EventCtx<'_, '_>, data: &mut State, event: types::Event) {
+        let sent = data
+            .events
+            .as_ref()
+            .map_or(false, |events| events.send(event).is_ok());
+        if !sent {
             tracing::error!("controlling thread stopped listening for events");
-            window.close();
+            self.end_session(ctx, data);
         }
     }
+
+    /// Close the window, or hide it until the next client if it's persistent
+    fn end_session(&self, ctx: &mut EventCtx<'_, '_>, data: &mut State) {
+        data.cancel_matching();
+        if !self.persistent {
+            ctx.window().close();
+            return;
+        }
+
+        if let Some(events) = mem::take(data).events {
+            if events.send(types::Event::WindowClosed).is_err() {
+                tracing::error!("failed to send WindowClosedEvent to the controlling thread");
+            }
+        }
+        ctx.window().hide();
+    }
 }
 
 impl<T> Controller<State, T> for TypeWatcher
@@ -84,50 +371,74 @@ where
                 key: Key::Enter, ..
             }) => {
                 if let Some(selected) = data.elems.selected {
-                    if let Some(option) = data.elems.options.iter().nth(selected) {
-                        self.send_event(ctx.window(), types::Event::Select(Some(option.id)));
+                    if let Some(choice) = data.elems.options.iter().nth(selected).cloned() {
+                        self.send_event(ctx, data, types::Event::Select(Some(Box::new(choice))));
                     } else {
                         tracing::error!(".elems is shorter than implied by selected");
                         data.elems.selected = None;
                     }
                 } else {
-                    self.send_event(ctx.window(), types::Event::Select(None));
+                    self.send_event(ctx, data, types::Event::Select(None));
+                }
+            }
+            Event::WindowConnected if self.persistent => {
+                // Window is shown only after it's connected, so hide it on the next iteration
+                self.hide_timer = Some(ctx.request_timer(Duration::from_millis(0)));
+            }
+            Event::Timer(token) if self.hide_timer == Some(*token) => {
+                self.hide_timer = None;
+                if data.client_id.is_none() {
+                    ctx.window().hide();
                 }
             }
+            Event::WindowCloseRequested if self.persistent => {
+                self.end_session(ctx, data);
+                ctx.set_handled();
+            }
             Event::Command(command) => {
-                if let Some(user_request) = command.get(CLIENT_REQUEST_SELECTOR) {
-                    match user_request {
-                        ClientRequest::Stop => ctx.window().close(),
-                        ClientRequest::SetChoices(choices) => {
-                            data.elems = choices.clone();
-                            if let Some(selected) = data.elems.selected {
-                                if selected >= choices.len() {
-                                    data.elems.selected = Some(choices.len() - 1);
-                                }
-                            }
-
-                            if data.matcher == Matcher::Fuzzy {
-                                data.elems.fuzzy_sort(&data.input);
-                            }
-                        }
-                        ClientRequest::SetInput(input) => {
-                            data.input = input.clone();
-                        }
+                if let Some((client_id, user_request)) = command.get(CLIENT_REQUEST_SELECTOR) {
+                    if data.client_id != Some(*client_id) {
+                        tracing::debug!(
+                            client_id = client_id,
+                            "ignoring request from a client not owning the window"
+                        );
+                        return;
+                    }
+
+                    if data.apply_request(user_request, Target::Window(ctx.window_id())) {
+                        self.end_session(ctx, data);
+                    }
+                }
+
+                if let Some(matched) = command.get(matching::MATCHED_SELECTOR) {
+                    if data.client_id == Some(matched.client_id) {
+                        data.finish_matching(matched);
                     }
                 }
 
                 if command.get(WINDOW_MOVED_SELECTOR).is_some() {
                     data.window_moved = true;
                 }
+
+                if let Some(&visible) = command.get(SET_VISIBLE_SELECTOR) {
+                    if visible {
+                        ctx.window().show();
+                    } else {
+                        ctx.window().hide();
+                    }
+                }
             }
             _ => {}
         }
 
         child.event(ctx, event, data, env);
 
-        if data.matcher == Matcher::Fuzzy && old_input != data.input {
-            data.elems.fuzzy_sort(&data.input);
-            data.elems.selected = Some(0).filter(|_| !data.elems.options.is_empty());
+        if old_input != data.input {
+            data.input_seq += 1;
+        }
+
+        if data.matcher != Matcher::None && old_input != data.input {
+            data.start_matching(Target::Window(ctx.window_id()), true);
         }
     }
 
@@ -139,15 +450,21 @@ where
         data: &State,
         env: &Env,
     ) {
-        if old_data.input != data.input {
-            self.events
-                .send(types::Event::InputChange(data.input.clone()))
-                .ok();
-        }
+        // Changes caused by switching to another client are not reported
+        if let (Some(events), true) = (&data.events, old_data.client_id == data.client_id) {
+            if old_data.input != data.input {
+                events
+                    .send(types::Event::InputChange {
+                        input: data.input.clone(),
+                        seq: data.input_seq,
+                    })
+                    .ok();
+            }
 
-        if old_data.elems.selected != data.elems.selected {
-            if let Some(selected) = data.elems.selected {
-                self.events.send(types::Event::CursorMove(selected)).ok();
+            if old_data.elems.selected != data.elems.selected {
+                if let Some(selected) = data.elems.selected {
+                    events.send(types::Event::CursorMove(selected)).ok();
+                }
             }
         }
 
@@ -199,7 +516,7 @@ where
                 ctx.submit_command(Command::new(
                     WINDOW_MOVED_SELECTOR,
                     WindowMoved,
-                    Target::Global,
+                    Target::Window(ctx.window_id()),
                 ));
 
                 if !actually_moved {
@@ -212,90 +529,473 @@ where
     }
 }
 
-fn root(events: Sender<types::Event>) -> impl Widget<State> {
-    Flex::column()
+/// Logs how long it took from accepting a client to drawing its window
+struct LatencyTrace<W> {
+    inner: W,
+    traced: Option<usize>,
+}
+
+impl<W: Widget<State>> Widget<State> for LatencyTrace<W> {
+    fn event(&mut self, ctx: &mut EventCtx<'_, '_>, event: &Event, data: &mut State, env: &Env) {
+        self.inner.event(ctx, event, data, env);
+    }
+
+    fn lifecycle(
+        &mut self,
+        ctx: &mut LifeCycleCtx<'_, '_>,
+        event: &LifeCycle,
+        data: &State,
+        env: &Env,
+    ) {
+        self.inner.lifecycle(ctx, event, data, env);
+    }
+
+    fn update(&mut self, ctx: &mut UpdateCtx<'_, '_>, old_data: &State, data: &State, env: &Env) {
+        self.inner.update(ctx, old_data, data, env);
+    }
+
+    fn layout(
+        &mut self,
+        ctx: &mut LayoutCtx<'_, '_>,
+        bc: &BoxConstraints,
+        data: &State,
+        env: &Env,
+    ) -> Size {
+        self.inner.layout(ctx, bc, data, env)
+    }
+
+    fn paint(&mut self, ctx: &mut PaintCtx<'_, '_, '_>, data: &State, env: &Env) {
+        self.inner.paint(ctx, data, env);
+
+        if let (Some(client_id), Some(accepted_at)) = (data.client_id, data.accepted_at) {
+            if self.traced != Some(client_id) {
+                self.traced = Some(client_id);
+                tracing::info!(
+                    client_id = client_id,
+                    "first paint {:?} after accepting the connection",
+                    accepted_at.elapsed()
+                );
+            }
+        }
+    }
+}
+
+fn root(persistent: bool) -> impl Widget<State> {
+    let column = Flex::column()
         .with_child(
             TextBox::new()
                 .with_placeholder("Query...")
                 .with_text_size(32.0)
                 .fix_width(512.0)
                 .lens(State::input)
-                .controller(TypeWatcher { events }),
+                .controller(TypeWatcher {
+                    persistent,
+                    hide_timer: None,
+                }),
         )
-        .with_child(
-            List::new(|| {
-                Label::new(|(_, item): &(Indices, Choice), _env: &_| String::from(&*item.text))
-                    .with_text_size(32.0)
-                    .with_text_alignment(druid::TextAlignment::Start)
-                    .fix_width(512.0)
-                    .background(Painter::new(
-                        move |paint, (idx, _): &(Indices, Choice), env| {
-                            let color = if idx.is_selected() {
-                                env.get(theme::SELECTED_TEXT_BACKGROUND_COLOR)
-                            } else {
-                                env.get(theme::WINDOW_BACKGROUND_COLOR)
-                            };
-
-                            let shape = paint.size().to_rect();
-                            paint.fill(shape, &color);
-                        },
-                    ))
+        .with_child(Either::new(
+            |data: &State, _env| data.match_error.is_some(),
+            Label::new(|data: &State, _env: &_| data.match_error.clone().unwrap_or_default())
+                .with_text_size(16.0)
+                .with_text_color(Color::rgb8(0xe0, 0x40, 0x40))
+                .with_text_alignment(druid::TextAlignment::Start)
+                .fix_width(512.0),
+            SizedBox::empty(),
+        ))
+        .with_child(Either::new(
+            |data: &State, _env| data.loading,
+            Label::new(|data: &State, _env: &_| {
+                format!("{} items, loading\u{2026}", data.elems.total_len())
             })
-            .lens(State::elems),
-        )
+            .with_text_size(16.0)
+            .with_text_alignment(druid::TextAlignment::Start)
+            .fix_width(512.0),
+            SizedBox::empty(),
+        ))
+        .with_child(ChoiceList::new(|| {
+            HighlightedLabel::new(
+                RawLabel::new()
+                    .with_text_size(32.0)
+                    .with_text_alignment(druid::TextAlignment::Start),
+            )
+            .lens(lens!((Indices, Choice), 1))
+            .fix_width(512.0)
+            .background(Painter::new(
+                move |paint, (idx, _): &(Indices, Choice), env| {
+                    let color = if idx.is_selected() {
+                        env.get(theme::SELECTED_TEXT_BACKGROUND_COLOR)
+                    } else {
+                        env.get(theme::WINDOW_BACKGROUND_COLOR)
+                    };
+
+                    let shape = paint.size().to_rect();
+                    paint.fill(shape, &color);
+                },
+            ))
+        }))
+        .with_child(Either::new(
+            |data: &State, _env| data.elems.is_empty() && !data.elems.hidden.is_empty(),
+            Label::new("No matches")
+                .with_text_size(32.0)
+                .with_text_alignment(druid::TextAlignment::Start)
+                .fix_width(512.0),
+            SizedBox::empty(),
+        ));
+
+    LatencyTrace {
+        inner: column,
+        traced: None,
+    }
 }
 
-#[must_use]
-pub fn window(events: Sender<types::Event>) -> WindowDesc<State> {
-    WindowDesc::new(root(events))
+fn window_desc<T: Data>(root: impl Widget<T> + 'static) -> WindowDesc<T> {
+    WindowDesc::new(root)
         .show_titlebar(false)
         .window_size_policy(druid::WindowSizePolicy::Content)
         .resizable(false)
         .title("uuis")
 }
 
+/// Persistent window shared by all clients
+#[must_use]
+pub fn window() -> WindowDesc<State> {
+    window_desc(root(true))
+}
+
+/// Way to reach the window of a single client
+#[derive(Clone)]
+pub struct Handle {
+    sink: ExtEventSink,
+    target: Target,
+    client_id: usize,
+}
+
+impl Handle {
+    /// Pass a request to the client's window
+    ///
+    /// # Errors
+    ///
+    /// Fails if the application is no longer running.
+    pub fn request(&self, request: ClientRequest) -> Result<(), ExtEventError> {
+        self.sink.submit_command(
+            CLIENT_REQUEST_SELECTOR,
+            Box::new((self.client_id, request)),
+            self.target,
+        )
+    }
+
+    /// Put the client's session aside until it's shown again
+    ///
+    /// # Errors
+    ///
+    /// Fails if the application is no longer running.
+    pub fn suspend(&self) -> Result<(), ExtEventError> {
+        self.sink
+            .submit_command(SUSPEND_SELECTOR, self.client_id, Target::Global)
+    }
+}
+
 pub struct InitialState {
     pub client_id: usize,
     pub events: Sender<types::Event>,
-    pub control: Sender<ExtEventSink>,
+    pub control: Sender<Handle>,
     pub matcher: Matcher,
+    pub ranking: Ranking,
+    /// Hide choices not matching the input
+    pub filter: bool,
+    pub case: Option<Case>,
+    /// Match ignoring diacritics and compatibility forms
+    pub normalize: bool,
+    /// Choices shown at once, `DEFAULT_MAX_LINES` if not set
+    pub max_lines: Option<usize>,
+    /// When the client's connection was accepted, for tracing latency
+    pub accepted_at: Option<Instant>,
 }
 
-pub fn run(chan: &Receiver<InitialState>) {
-    loop {
-        let init = match chan.recv() {
-            Ok(init) => init,
-            Err(err) => {
-                tracing::error!("lost connection to the main thread: {}", err);
-                break;
+pub enum Request {
+    /// Show the window for a client, restoring its state if it was suspended
+    Show(InitialState),
+    /// Forget the state of a suspended client
+    Discard(usize),
+}
+
+/// Pass requests from the main thread to the application
+fn forward_requests(chan: Receiver<Request>, sink: ExtEventSink) {
+    thread::spawn(move || {
+        for request in &chan {
+            let result = match request {
+                Request::Show(init) => {
+                    sink.submit_command(OPEN_SELECTOR, SingleUse::new(init), Target::Global)
+                }
+                Request::Discard(client_id) => {
+                    sink.submit_command(DISCARD_SELECTOR, client_id, Target::Global)
+                }
+            };
+            if let Err(err) = result {
+                tracing::error!("application stopped: {}", err);
+                return;
             }
-        };
+        }
+        tracing::error!("lost connection to the main thread");
+    });
+}
+
+/// Switches the persistent window between clients in single-window mode
+struct SingleWindow {
+    sink: ExtEventSink,
+    engine: matching::Engine,
+    suspended: HashMap<usize, State>,
+}
 
-        let _span = tracing::info_span!("ui-iteration", client_id = init.client_id);
+impl SingleWindow {
+    fn suspend_current(&mut self, data: &mut State) {
+        if let Some(client_id) = data.client_id {
+            tracing::info!(client_id = client_id, "suspending session");
+            self.suspended.insert(client_id, mem::take(data));
+        }
+    }
+}
 
-        tracing::info!("received request to start UI");
-        let window = window(init.events.clone());
-        let launcher = AppLauncher::with_window(window);
-        let control = launcher.get_external_handle();
+impl AppDelegate<State> for SingleWindow {
+    fn command(
+        &mut self,
+        ctx: &mut DelegateCtx<'_>,
+        _target: Target,
+        cmd: &Command,
+        data: &mut State,
+        _env: &Env,
+    ) -> Handled {
+        if let Some(init) = cmd.get(OPEN_SELECTOR).and_then(SingleUse::take) {
+            let span = tracing::info_span!("ui-open", client_id = init.client_id);
+            let _entered = span.enter();
+
+            // Session still open here is being preempted
+            self.suspend_current(data);
+
+            let control = Handle {
+                sink: self.sink.clone(),
+                target: Target::Global,
+                client_id: init.client_id,
+            };
+            if init.control.send(control).is_err() {
+                tracing::error!("failed to send window handle to the controlling thread");
+                return Handled::Yes;
+            }
 
-        if init.control.send(control).is_err() {
-            tracing::error!("failed to send ExtEventSink to the controlling thread");
-            continue;
+            *data = match self.suspended.remove(&init.client_id) {
+                Some(state) => {
+                    tracing::info!("restoring suspended session");
+                    State {
+                        events: Some(init.events),
+                        accepted_at: None,
+                        window_moved: false,
+                        ..state
+                    }
+                }
+                None => State::new(&init, self.engine.clone()),
+            };
+            ctx.submit_command(Command::new(SET_VISIBLE_SELECTOR, true, Target::Global));
+            Handled::Yes
+        } else if let Some(&client_id) = cmd.get(SUSPEND_SELECTOR) {
+            if data.client_id == Some(client_id) {
+                self.suspend_current(data);
+                ctx.submit_command(Command::new(SET_VISIBLE_SELECTOR, false, Target::Global));
+            }
+            Handled::Yes
+        } else if let Some(client_id) = cmd.get(DISCARD_SELECTOR) {
+            self.suspended.remove(client_id);
+            Handled::Yes
+        } else if let Some((client_id, request)) = cmd.get(CLIENT_REQUEST_SELECTOR) {
+            // Requests sent just before the client learned it was preempted
+            let state = match self.suspended.get_mut(client_id) {
+                Some(state) => state,
+                None => return Handled::No,
+            };
+
+            let old_input = state.input.clone();
+            // Results are handled by this delegate until the session is restored
+            if state.apply_request(request, Target::Global) {
+                state.cancel_matching();
+                self.suspended.remove(client_id);
+            } else if state.input != old_input {
+                state.input_seq += 1;
+                if state.matcher != Matcher::None {
+                    state.start_matching(Target::Global, true);
+                }
+            }
+            Handled::Yes
+        } else if let Some(matched) = cmd.get(matching::MATCHED_SELECTOR) {
+            // Results for the current session are handled by the window
+            match self.suspended.get_mut(&matched.client_id) {
+                Some(state) => {
+                    state.finish_matching(matched);
+                    Handled::Yes
+                }
+                None => Handled::No,
+            }
+        } else {
+            Handled::No
         }
+    }
+}
 
-        if let Err(err) = launcher.launch(State {
-            matcher: init.matcher,
-            ..State::default()
-        }) {
-            tracing::error!("failed to create a new window: {}", err);
-            break;
+/// Run the UI with a single window, reused by every client in turn
+pub fn run(chan: &Receiver<Request>) {
+    let launcher = AppLauncher::with_window(window());
+    let sink = launcher.get_external_handle();
+    let launcher = launcher.delegate(SingleWindow {
+        sink: sink.clone(),
+        engine: matching::Engine::start(sink.clone()),
+        suspended: HashMap::new(),
+    });
+
+    forward_requests(chan.clone(), sink);
+    if let Err(err) = launcher.launch(State::default()) {
+        tracing::error!("failed to start the application: {}", err);
+    }
+}
+
+/// Application data in multi-window mode: state of every client's window
+#[derive(Debug, Default, Clone, Data)]
+pub struct Sessions(im::OrdMap<usize, State>);
+
+/// Lens to the state of a single client's window
+struct SessionLens(usize);
+
+impl Lens<Sessions, State> for SessionLens {
+    fn with<V, F: FnOnce(&State) -> V>(&self, data: &Sessions, f: F) -> V {
+        // Window may briefly outlive its session while closing
+        match data.0.get(&self.0) {
+            Some(state) => f(state),
+            None => f(&State::default()),
+        }
+    }
+
+    fn with_mut<V, F: FnOnce(&mut State) -> V>(&self, data: &mut Sessions, f: F) -> V {
+        match data.0.get_mut(&self.0) {
+            Some(state) => f(state),
+            None => f(&mut State::default()),
+        }
+    }
+}
+
+/// Invisible window keeping the application running while no client has a window open
+struct Anchor;
+
+impl<T> Widget<T> for Anchor {
+    fn event(&mut self, ctx: &mut EventCtx<'_, '_>, event: &Event, _data: &mut T, _env: &Env) {
+        match event {
+            // Window is shown only after it's connected, so hide it on the next iteration
+            Event::WindowConnected => {
+                ctx.request_timer(Duration::from_millis(0));
+            }
+            Event::Timer(_) => ctx.window().hide(),
+            _ => {}
+        }
+    }
+
+    fn lifecycle(
+        &mut self,
+        _ctx: &mut LifeCycleCtx<'_, '_>,
+        _event: &LifeCycle,
+        _data: &T,
+        _env: &Env,
+    ) {
+    }
+
+    fn update(&mut self, _ctx: &mut UpdateCtx<'_, '_>, _old_data: &T, _data: &T, _env: &Env) {}
+
+    fn layout(
+        &mut self,
+        _ctx: &mut LayoutCtx<'_, '_>,
+        bc: &BoxConstraints,
+        _data: &T,
+        _env: &Env,
+    ) -> Size {
+        bc.min()
+    }
+
+    fn paint(&mut self, _ctx: &mut PaintCtx<'_, '_, '_>, _data: &T, _env: &Env) {}
+}
+
+/// Opens and closes client windows in multi-window mode
+struct MultiWindow {
+    sink: ExtEventSink,
+    engine: matching::Engine,
+    windows: HashMap<WindowId, usize>,
+}
+
+impl AppDelegate<Sessions> for MultiWindow {
+    fn command(
+        &mut self,
+        ctx: &mut DelegateCtx<'_>,
+        _target: Target,
+        cmd: &Command,
+        data: &mut Sessions,
+        _env: &Env,
+    ) -> Handled {
+        let init = match cmd.get(OPEN_SELECTOR).and_then(SingleUse::take) {
+            Some(init) => init,
+            None => return Handled::No,
+        };
+
+        let span = tracing::info_span!("ui-open", client_id = init.client_id);
+        let _entered = span.enter();
+        tracing::info!("opening a new window");
+
+        let desc = window_desc(root(false).lens(SessionLens(init.client_id)));
+        let control = Handle {
+            sink: self.sink.clone(),
+            target: Target::Window(desc.id),
+            client_id: init.client_id,
+        };
+        if init.control.send(control).is_err() {
+            tracing::error!("failed to send window handle to the controlling thread");
+            return Handled::Yes;
         }
 
-        tracing::info!("window closed, looping");
+        data.0
+            .insert(init.client_id, State::new(&init, self.engine.clone()));
+        self.windows.insert(desc.id, init.client_id);
+        ctx.new_window(desc);
+        Handled::Yes
+    }
 
-        if init.events.send(types::Event::WindowClosed).is_err() {
-            tracing::error!("failed to send WindowClosedEvent to the controlling thread");
-            continue;
+    fn window_removed(
+        &mut self,
+        id: WindowId,
+        data: &mut Sessions,
+        _env: &Env,
+        _ctx: &mut DelegateCtx<'_>,
+    ) {
+        let client_id = match self.windows.remove(&id) {
+            Some(client_id) => client_id,
+            None => return,
+        };
+
+        if let Some(events) = data.0.remove(&client_id).and_then(|state| state.events) {
+            if events.send(types::Event::WindowClosed).is_err() {
+                tracing::error!(
+                    client_id = client_id,
+                    "failed to send WindowClosedEvent to the controlling thread"
+                );
+            }
         }
     }
 }
+
+/// Run the UI with a separate window for every client
+pub fn run_multi(chan: &Receiver<Request>) {
+    let launcher = AppLauncher::with_window(WindowDesc::new(Anchor).show_titlebar(false));
+    let sink = launcher.get_external_handle();
+    let launcher = launcher.delegate(MultiWindow {
+        sink: sink.clone(),
+        engine: matching::Engine::start(sink.clone()),
+        windows: HashMap::new(),
+    });
+
+    forward_requests(chan.clone(), sink);
+    if let Err(err) = launcher.launch(Sessions::default()) {
+        tracing::error!("failed to start the application: {}", err);
+    }
+}
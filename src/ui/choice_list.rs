@@ -0,0 +1,138 @@
+use druid::{widget::prelude::*, Point, WidgetPod};
+
+use super::State;
+use crate::types::{Choice, Indices};
+
+type Row = WidgetPod<(Indices, Choice), Box<dyn Widget<(Indices, Choice)>>>;
+
+/// List of choices laying out only the rows that fit into `State::max_lines`
+///
+/// Rows are reused while scrolling, so the number of widgets doesn't depend on
+/// the number of choices.
+pub struct ChoiceList {
+    make_row: Box<dyn Fn() -> Box<dyn Widget<(Indices, Choice)>>>,
+    rows: Vec<Row>,
+    /// Index of the first visible choice
+    offset: usize,
+}
+
+impl ChoiceList {
+    pub fn new<W>(make_row: impl Fn() -> W + 'static) -> Self
+    where
+        W: Widget<(Indices, Choice)> + 'static,
+    {
+        let make_row = move || -> Box<dyn Widget<(Indices, Choice)>> { Box::new(make_row()) };
+        ChoiceList {
+            make_row: Box::new(make_row),
+            rows: Vec::new(),
+            offset: 0,
+        }
+    }
+
+    fn visible(data: &State) -> usize {
+        data.elems.len().min(data.max_lines.max(1))
+    }
+
+    /// Move visible rows so the selected choice is among them
+    fn scroll_to_selected(&mut self, data: &State) {
+        let visible = Self::visible(data);
+        if let Some(selected) = data.elems.selected {
+            if selected < self.offset {
+                self.offset = selected;
+            } else if selected >= self.offset + visible {
+                self.offset = selected + 1 - visible;
+            }
+        }
+        self.offset = self.offset.min(data.elems.len() - visible);
+    }
+
+    /// Create or drop rows to match the number of visible choices, returning whether it changed
+    fn update_row_count(&mut self, data: &State) -> bool {
+        let visible = Self::visible(data);
+        let count = self.rows.len();
+        if count > visible {
+            self.rows.truncate(visible);
+        } else {
+            let make_row = &self.make_row;
+            self.rows
+                .extend((count..visible).map(|_| WidgetPod::new(make_row())));
+        }
+        count != visible
+    }
+
+    fn for_each_row(&mut self, data: &State, mut cb: impl FnMut(&mut Row, &(Indices, Choice))) {
+        let selected = data.elems.selected;
+        let items = data.elems.options.iter().enumerate().skip(self.offset);
+        for (row, (current, item)) in self.rows.iter_mut().zip(items) {
+            cb(row, &(Indices { current, selected }, item.clone()));
+        }
+    }
+}
+
+impl Widget<State> for ChoiceList {
+    fn event(&mut self, ctx: &mut EventCtx<'_, '_>, event: &Event, data: &mut State, env: &Env) {
+        // Rows only display choices, so any changes they make are dropped
+        self.for_each_row(data, |row, row_data| {
+            row.event(ctx, event, &mut row_data.clone(), env);
+        });
+    }
+
+    fn lifecycle(
+        &mut self,
+        ctx: &mut LifeCycleCtx<'_, '_>,
+        event: &LifeCycle,
+        data: &State,
+        env: &Env,
+    ) {
+        if let LifeCycle::WidgetAdded = event {
+            self.scroll_to_selected(data);
+            if self.update_row_count(data) {
+                ctx.children_changed();
+            }
+        }
+
+        self.for_each_row(data, |row, row_data| {
+            row.lifecycle(ctx, event, row_data, env)
+        });
+    }
+
+    fn update(&mut self, ctx: &mut UpdateCtx<'_, '_>, _old_data: &State, data: &State, env: &Env) {
+        let old_offset = self.offset;
+        self.scroll_to_selected(data);
+        self.for_each_row(data, |row, row_data| row.update(ctx, row_data, env));
+
+        if self.update_row_count(data) {
+            ctx.children_changed();
+        } else if old_offset != self.offset {
+            ctx.request_layout();
+        }
+    }
+
+    fn layout(
+        &mut self,
+        ctx: &mut LayoutCtx<'_, '_>,
+        bc: &BoxConstraints,
+        data: &State,
+        env: &Env,
+    ) -> Size {
+        let row_bc = BoxConstraints::new(
+            Size::new(bc.min().width, 0.0),
+            Size::new(bc.max().width, f64::INFINITY),
+        );
+        let mut width = bc.min().width;
+        let mut y = 0.0;
+
+        self.for_each_row(data, |row, row_data| {
+            let size = row.layout(ctx, &row_bc, row_data, env);
+            row.set_origin(ctx, row_data, env, Point::new(0.0, y));
+            width = width.max(size.width);
+            y += size.height;
+        });
+
+        bc.constrain(Size::new(width, y))
+    }
+
+    fn paint(&mut self, ctx: &mut PaintCtx<'_, '_, '_>, data: &State, env: &Env) {
+        self.for_each_row(data, |row, row_data| row.paint(ctx, row_data, env));
+    }
+}
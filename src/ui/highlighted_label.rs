@@ -0,0 +1,97 @@
+use std::mem;
+
+use druid::{
+    text::{RichText, RichTextBuilder},
+    theme,
+    widget::{prelude::*, RawLabel},
+    FontWeight,
+};
+
+use crate::types::Choice;
+
+/// Text of a choice with the characters matching the input highlighted
+///
+/// The rich text is rebuilt only when the text or the matched characters change, since
+/// a new `RichText` is never `same` as the old one and would relayout the label.
+pub struct HighlightedLabel {
+    label: RawLabel<RichText>,
+    text: RichText,
+}
+
+impl HighlightedLabel {
+    pub fn new(label: RawLabel<RichText>) -> Self {
+        HighlightedLabel {
+            label,
+            text: RichText::new("".into()),
+        }
+    }
+}
+
+impl Widget<Choice> for HighlightedLabel {
+    fn event(&mut self, ctx: &mut EventCtx<'_, '_>, event: &Event, _data: &mut Choice, env: &Env) {
+        self.label.event(ctx, event, &mut self.text, env);
+    }
+
+    fn lifecycle(
+        &mut self,
+        ctx: &mut LifeCycleCtx<'_, '_>,
+        event: &LifeCycle,
+        data: &Choice,
+        env: &Env,
+    ) {
+        if let LifeCycle::WidgetAdded = event {
+            self.text = highlighted(data);
+        }
+        self.label.lifecycle(ctx, event, &self.text, env);
+    }
+
+    fn update(&mut self, ctx: &mut UpdateCtx<'_, '_>, old_data: &Choice, data: &Choice, env: &Env) {
+        // Rescoring creates new positions even if they're equal to the old ones
+        if old_data.text != data.text || old_data.positions != data.positions {
+            let old_text = mem::replace(&mut self.text, highlighted(data));
+            self.label.update(ctx, &old_text, &self.text, env);
+        }
+    }
+
+    fn layout(
+        &mut self,
+        ctx: &mut LayoutCtx<'_, '_>,
+        bc: &BoxConstraints,
+        _data: &Choice,
+        env: &Env,
+    ) -> Size {
+        self.label.layout(ctx, bc, &self.text, env)
+    }
+
+    fn paint(&mut self, ctx: &mut PaintCtx<'_, '_, '_>, _data: &Choice, env: &Env) {
+        self.label.paint(ctx, &self.text, env);
+    }
+}
+
+fn highlighted(item: &Choice) -> RichText {
+    let mut builder = RichTextBuilder::new();
+    let mut push = |run: &str, matched: bool| {
+        let mut attrs = builder.push(run);
+        if matched {
+            attrs
+                .weight(FontWeight::BOLD)
+                .text_color(theme::PRIMARY_LIGHT);
+        }
+    };
+
+    let mut positions = item.positions.iter().copied().peekable();
+    let mut run = String::new();
+    let mut run_matched = false;
+    for (idx, ch) in item.text.chars().enumerate() {
+        let matched = positions.next_if_eq(&idx).is_some();
+        if matched != run_matched && !run.is_empty() {
+            push(&run, run_matched);
+            run.clear();
+        }
+        run_matched = matched;
+        run.push(ch);
+    }
+    push(&run, run_matched);
+
+    builder.build()
+}
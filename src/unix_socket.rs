@@ -0,0 +1,80 @@
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    os::unix::{
+        fs::{FileTypeExt as _, MetadataExt as _},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+};
+
+/// Socket file owned by this server, removed on drop
+#[derive(Debug)]
+pub struct SocketFile {
+    path: PathBuf,
+    inode: u64,
+}
+
+/// Bind to `path`, replacing the socket file if no one is listening on it
+pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<(UnixListener, SocketFile)> {
+    let path = path.as_ref();
+    let listener = match UnixListener::bind(path) {
+        Ok(listener) => listener,
+        Err(err) if err.kind() == ErrorKind::AddrInUse => {
+            remove_stale(path)?;
+            UnixListener::bind(path)?
+        }
+        Err(err) => return Err(err),
+    };
+
+    let file = SocketFile {
+        path: path.to_owned(),
+        inode: fs::metadata(path)?.ino(),
+    };
+    Ok((listener, file))
+}
+
+/// Remove socket file at `path` if it's left over from a dead server
+///
+/// Anything but a socket is left alone, since binding fails on any existing file.
+fn remove_stale(path: &Path) -> io::Result<()> {
+    if !fs::symlink_metadata(path)?.file_type().is_socket() {
+        return Err(io::Error::new(
+            ErrorKind::AddrInUse,
+            format!("{} exists and is not a socket", path.display()),
+        ));
+    }
+
+    // The probe is closed right away; servers ignore clients leaving before registering
+    match UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(
+            ErrorKind::AddrInUse,
+            format!(
+                "another uuis instance is already listening on {}",
+                path.display()
+            ),
+        )),
+        Err(err) if err.kind() == ErrorKind::ConnectionRefused => {
+            tracing::warn!("removing stale socket {}", path.display());
+            fs::remove_file(path)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        // Don't remove the socket if someone else has already replaced it
+        match fs::metadata(&self.path) {
+            Ok(meta) if meta.ino() == self.inode => {
+                if let Err(err) = fs::remove_file(&self.path) {
+                    tracing::warn!("failed to remove socket {}: {}", self.path.display(), err);
+                }
+            }
+            Ok(_) => {}
+            Err(err) => {
+                tracing::warn!("failed to stat socket {}: {}", self.path.display(), err);
+            }
+        }
+    }
+}
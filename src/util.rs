@@ -0,0 +1,22 @@
+use std::{env, path::PathBuf};
+
+/// `$XDG_RUNTIME_DIR`, if it is set and non-empty
+#[must_use]
+pub fn runtime_dir() -> Option<PathBuf> {
+    env::var_os("XDG_RUNTIME_DIR")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+}
+
+/// `$XDG_STATE_HOME`, falling back to `~/.local/state` as the spec requires
+#[must_use]
+pub fn state_dir() -> Option<PathBuf> {
+    env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            env::var_os("HOME")
+                .filter(|dir| !dir.is_empty())
+                .map(|home| PathBuf::from(home).join(".local/state"))
+        })
+}